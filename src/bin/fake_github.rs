@@ -0,0 +1,126 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Context, Result};
+use clap::{crate_authors, crate_version, App, Arg};
+use log::{debug, info, warn};
+use pr_commentator::fake::{FailureRule, FakeGithub, FakeRequest, FakeState};
+use tiny_http::{Header, Request, Response, Server};
+
+fn serve(github: &Mutex<FakeGithub>, mut request: Request) -> Result<()> {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv(name))
+            .map(|header| header.value.as_str().to_owned())
+    };
+    let mut fake_request = FakeRequest {
+        method: request.method().to_string(),
+        url: request.url().to_owned(),
+        host: header("Host").unwrap_or_else(|| "localhost".to_owned()),
+        authorization: header("Authorization"),
+        body: String::new(),
+    };
+    request
+        .as_reader()
+        .read_to_string(&mut fake_request.body)
+        .context("Failed to read request body")?;
+
+    // Answered under the lock, but delayed outside of it not to slow down the other requests
+    let response = github
+        .lock()
+        .map_err(|_| anyhow!("Fake state poisoned"))?
+        .handle(&fake_request);
+    if let Some(delay) = response.delay {
+        debug!("Delaying response by {:?}", delay);
+        thread::sleep(delay);
+    }
+    info!(
+        "{} {} -> {}",
+        fake_request.method, fake_request.url, response.status
+    );
+
+    let mut http_response = Response::from_string(response.body).with_status_code(response.status);
+    for (name, value) in response.headers {
+        let header = Header::from_bytes(name.as_bytes(), value.as_bytes())
+            .map_err(|_| anyhow!("Invalid header {}: {}", name, value))?;
+        http_response = http_response.with_header(header);
+    }
+    request
+        .respond(http_response)
+        .context("Failed to send response")
+}
+
+fn main() -> Result<()> {
+    env_logger::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let address_arg = Arg::with_name("Address")
+        .long("address")
+        .help("The address to listen on")
+        .default_value("127.0.0.1:8080")
+        .takes_value(true);
+    let state_arg = Arg::with_name("State")
+        .long("state")
+        .help("A JSON file with the initial state, as dumped by `GET /_fake/state`")
+        .takes_value(true);
+    let failure_arg = Arg::with_name("Failure")
+        .long("failure")
+        .help(
+            "A failure to inject, as JSON, e.g. \
+             `{\"path\": \"/repos/o/r/issues\", \"method\": \"POST\", \"status\": 502, \"times\": 2}`",
+        )
+        .multiple(true)
+        .number_of_values(1)
+        .takes_value(true);
+    let app = App::new("fake-github")
+        .version(crate_version!())
+        .about(
+            "A fake of the Github REST endpoints used by pr-commentator, keeping its state in \
+             memory. Point pr-commentator at it with `--api-url http://<address>/`",
+        )
+        .long_about(
+            "A fake of the Github REST endpoints used by pr-commentator, keeping its state in \
+             memory. Point pr-commentator at it with `--api-url http://<address>/`.\n\
+             It serves the PRs listing, the issue comments and their reactions, the commit \
+             statuses, the check runs and the gists. It is driven through `POST /_fake/pulls` to add a PR, \
+             `POST /_fake/failures` to inject a failure (`DELETE` to remove them), \
+             `GET /_fake/state` to dump the state (`PUT` to replace it) and `POST /_fake/reset`.",
+        )
+        .author(crate_authors!())
+        .arg(&address_arg)
+        .arg(&state_arg)
+        .arg(&failure_arg)
+        .get_matches();
+
+    let state = match app.value_of(state_arg.b.name) {
+        Some(file) => serde_json::from_slice::<FakeState>(
+            &fs::read(file).with_context(|| format!("Failed to read state file {}", file))?,
+        )
+        .with_context(|| format!("Invalid state file {}", file))?,
+        None => FakeState::default(),
+    };
+    let mut github = FakeGithub::new(state);
+    for failure in app.values_of(failure_arg.b.name).into_iter().flatten() {
+        github.failures.push(
+            serde_json::from_str::<FailureRule>(failure)
+                .with_context(|| format!("Invalid failure {}", failure))?,
+        );
+    }
+
+    let address = app.value_of(address_arg.b.name).unwrap();
+    let server =
+        Server::http(address).map_err(|e| anyhow!("Failed to listen on {} : {}", address, e))?;
+    info!("Fake Github listening on http://{}/", server.server_addr());
+    let github = Arc::new(Mutex::new(github));
+    for request in server.incoming_requests() {
+        let github = github.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(&github, request) {
+                warn!("{:#}", e);
+            }
+        });
+    }
+    Ok(())
+}
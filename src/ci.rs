@@ -0,0 +1,512 @@
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use serde_json::Value;
+use url::Url;
+
+use crate::github::{get_repo_info_from_url, RepoInfo};
+
+/// Access to the environment variables, abstracted to be able to test the providers
+pub trait Environment {
+    fn var(&self, name: &str) -> Option<String>;
+}
+
+/// The environment of the current process, empty variables are considered absent
+pub struct ProcessEnvironment;
+
+impl Environment for ProcessEnvironment {
+    fn var(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok().filter(|v| !v.is_empty())
+    }
+}
+
+#[cfg(test)]
+impl Environment for std::collections::HashMap<&str, &str> {
+    fn var(&self, name: &str) -> Option<String> {
+        self.get(name).map(|v| v.to_string())
+    }
+}
+
+/// What a CI provider could deduce from its environment, every field is optional
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CiContext {
+    pub provider: &'static str,
+    pub api_url: Option<Url>,
+    pub repo_owner: Option<String>,
+    pub repo_name: Option<String>,
+    pub git_ref: Option<String>,
+    pub sha: Option<String>,
+    pub pr_number: Option<u64>,
+}
+
+impl CiContext {
+    fn new(provider: &'static str) -> CiContext {
+        CiContext {
+            provider,
+            ..CiContext::default()
+        }
+    }
+
+    fn with_repo_info(mut self, repo_info: Option<RepoInfo>) -> CiContext {
+        if let Some(repo_info) = repo_info {
+            self.api_url = Some(repo_info.api_url);
+            self.repo_owner = Some(repo_info.org);
+            self.repo_name = Some(repo_info.name);
+        }
+        self
+    }
+
+    fn with_slug(mut self, slug: Option<String>) -> CiContext {
+        if let Some((owner, name)) = slug.as_deref().and_then(split_slug) {
+            self.repo_owner = Some(owner.to_owned());
+            self.repo_name = Some(name.to_owned());
+        }
+        self
+    }
+}
+
+impl fmt::Display for CiContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn or_none<T: fmt::Display>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| "<none>".to_owned())
+        }
+        writeln!(f, "provider:   {}", self.provider)?;
+        writeln!(f, "api url:    {}", or_none(&self.api_url))?;
+        writeln!(f, "repo owner: {}", or_none(&self.repo_owner))?;
+        writeln!(f, "repo name:  {}", or_none(&self.repo_name))?;
+        writeln!(f, "git ref:    {}", or_none(&self.git_ref))?;
+        writeln!(f, "sha:        {}", or_none(&self.sha))?;
+        write!(f, "PR number:  {}", or_none(&self.pr_number))
+    }
+}
+
+/// A CI system able to describe the build it is running
+pub trait CiProvider {
+    fn name(&self) -> &'static str;
+
+    /// Return the context if the environment belongs to this provider
+    fn detect(&self, env: &dyn Environment) -> Option<CiContext>;
+}
+
+/// Every supported provider, in the order they are tried
+pub fn providers() -> Vec<Box<dyn CiProvider>> {
+    vec![
+        Box::new(GithubActions),
+        Box::new(GitlabCi),
+        Box::new(Jenkins),
+        Box::new(Buildkite),
+        Box::new(CircleCi),
+        Box::new(Travis),
+    ]
+}
+
+/// Return the context of the first provider recognising the environment
+pub fn detect_context(env: &dyn Environment) -> Option<CiContext> {
+    providers().iter().find_map(|provider| {
+        let context = provider.detect(env);
+        if context.is_some() {
+            debug!("Detected CI provider {}", provider.name());
+        }
+        context
+    })
+}
+
+fn split_slug(slug: &str) -> Option<(&str, &str)> {
+    let mut parts = slug.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(owner), Some(name)) if !owner.is_empty() && !name.is_empty() => Some((owner, name)),
+        _ => None,
+    }
+}
+
+/// Parse a PR number, CI tools use `false` or an empty string when not building a PR
+fn parse_pr_number(value: Option<String>) -> Option<u64> {
+    value.and_then(|v| u64::from_str(&v).ok())
+}
+
+/// Extract the PR number from a PR url (e.g. `https://github.com/org/repo/pull/12`)
+fn pr_number_from_url(url: Option<String>) -> Option<u64> {
+    url.and_then(|url| {
+        url.trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .and_then(|n| u64::from_str(n).ok())
+    })
+}
+
+/// Deduce the repo info from a clone url, accepting the scp-like syntax used for ssh
+fn repo_info_from_clone_url(clone_url: &str) -> Result<RepoInfo> {
+    let normalized = if clone_url.contains("://") {
+        clone_url.to_owned()
+    } else if let Some(pos) = clone_url.find(':') {
+        let host = clone_url[..pos].rsplit('@').next().unwrap_or_default();
+        format!("https://{}/{}", host, &clone_url[pos + 1..])
+    } else {
+        return Err(anyhow!("Unsupported clone url {}", clone_url));
+    };
+    let mut url =
+        Url::from_str(&normalized).with_context(|| format!("Invalid clone url `{}`", clone_url))?;
+    url.set_scheme("https")
+        .map_err(|_| anyhow!("Can't convert {} to an https url", clone_url))?;
+    url.set_username("")
+        .map_err(|_| anyhow!("Can't strip user from {}", clone_url))?;
+    get_repo_info_from_url(url, None)
+}
+
+fn repo_info_from_env(env: &dyn Environment, var: &str) -> Option<RepoInfo> {
+    env.var(var)
+        .and_then(|clone_url| match repo_info_from_clone_url(&clone_url) {
+            Ok(repo_info) => Some(repo_info),
+            Err(e) => {
+                warn!("Ignoring {}: {}", var, e);
+                None
+            }
+        })
+}
+
+/// Extract the PR number from the json payload of a Github Actions event
+pub fn pr_number_from_github_event(event: &str) -> Result<Option<u64>> {
+    let event: Value = serde_json::from_str(event).context("Invalid event json")?;
+    let number = event
+        .pointer("/pull_request/number")
+        .or_else(|| {
+            // Comments on PRs are delivered as issue events
+            event
+                .pointer("/issue/pull_request")
+                .and_then(|_| event.pointer("/issue/number"))
+        })
+        .and_then(Value::as_u64);
+    Ok(number)
+}
+
+/// Github Actions, see https://docs.github.com/en/actions/learn-github-actions/environment-variables
+pub struct GithubActions;
+
+impl CiProvider for GithubActions {
+    fn name(&self) -> &'static str {
+        "GitHub Actions"
+    }
+
+    fn detect(&self, env: &dyn Environment) -> Option<CiContext> {
+        env.var("GITHUB_ACTIONS")?;
+        let mut context = CiContext::new(self.name()).with_slug(env.var("GITHUB_REPOSITORY"));
+        context.api_url = env.var("GITHUB_API_URL").and_then(|api_url| {
+            // The variable has no trailing slash which would break relative url resolution
+            Url::from_str(&format!("{}/", api_url.trim_end_matches('/'))).ok()
+        });
+        context.git_ref = env.var("GITHUB_REF");
+        context.sha = env.var("GITHUB_SHA");
+        context.pr_number = env.var("GITHUB_EVENT_PATH").and_then(|event_path| {
+            fs::read_to_string(&event_path)
+                .with_context(|| format!("Failed to read {}", event_path))
+                .and_then(|event| pr_number_from_github_event(&event))
+                .unwrap_or_else(|e| {
+                    warn!("Ignoring GITHUB_EVENT_PATH: {:#}", e);
+                    None
+                })
+        });
+        Some(context)
+    }
+}
+
+/// Gitlab CI building a Github mirror through external pull request pipelines
+pub struct GitlabCi;
+
+impl CiProvider for GitlabCi {
+    fn name(&self) -> &'static str {
+        "GitLab CI"
+    }
+
+    fn detect(&self, env: &dyn Environment) -> Option<CiContext> {
+        env.var("GITLAB_CI")?;
+        let mut context = CiContext::new(self.name())
+            .with_slug(env.var("CI_EXTERNAL_PULL_REQUEST_TARGET_REPOSITORY"));
+        context.git_ref = env
+            .var("CI_EXTERNAL_PULL_REQUEST_SOURCE_BRANCH_NAME")
+            .or_else(|| env.var("CI_COMMIT_REF_NAME"));
+        context.sha = env
+            .var("CI_EXTERNAL_PULL_REQUEST_SOURCE_BRANCH_SHA")
+            .or_else(|| env.var("CI_COMMIT_SHA"));
+        context.pr_number = parse_pr_number(env.var("CI_EXTERNAL_PULL_REQUEST_IID"));
+        Some(context)
+    }
+}
+
+/// Jenkins, with the variables set by the git and Github branch source plugins
+pub struct Jenkins;
+
+impl CiProvider for Jenkins {
+    fn name(&self) -> &'static str {
+        "Jenkins"
+    }
+
+    fn detect(&self, env: &dyn Environment) -> Option<CiContext> {
+        env.var("JENKINS_URL")?;
+        let mut context =
+            CiContext::new(self.name()).with_repo_info(repo_info_from_env(env, "GIT_URL"));
+        context.git_ref = env
+            .var("CHANGE_BRANCH")
+            // Prefixed by the remote, e.g. `origin/my_branch`
+            .or_else(|| {
+                env.var("GIT_BRANCH").map(|branch| {
+                    branch
+                        .strip_prefix("origin/")
+                        .map(ToOwned::to_owned)
+                        .unwrap_or(branch)
+                })
+            })
+            .or_else(|| env.var("BRANCH_NAME"));
+        context.sha = env.var("GIT_COMMIT");
+        context.pr_number = parse_pr_number(env.var("CHANGE_ID"));
+        Some(context)
+    }
+}
+
+/// Buildkite, see https://buildkite.com/docs/pipelines/environment-variables
+pub struct Buildkite;
+
+impl CiProvider for Buildkite {
+    fn name(&self) -> &'static str {
+        "Buildkite"
+    }
+
+    fn detect(&self, env: &dyn Environment) -> Option<CiContext> {
+        env.var("BUILDKITE")?;
+        let mut context =
+            CiContext::new(self.name()).with_repo_info(repo_info_from_env(env, "BUILDKITE_REPO"));
+        context.git_ref = env.var("BUILDKITE_BRANCH");
+        context.sha = env.var("BUILDKITE_COMMIT").filter(|sha| sha != "HEAD");
+        context.pr_number = parse_pr_number(env.var("BUILDKITE_PULL_REQUEST"));
+        Some(context)
+    }
+}
+
+/// CircleCI, see https://circleci.com/docs/variables/
+pub struct CircleCi;
+
+impl CiProvider for CircleCi {
+    fn name(&self) -> &'static str {
+        "CircleCI"
+    }
+
+    fn detect(&self, env: &dyn Environment) -> Option<CiContext> {
+        env.var("CIRCLECI")?;
+        let mut context = CiContext::new(self.name())
+            .with_repo_info(repo_info_from_env(env, "CIRCLE_REPOSITORY_URL"));
+        if let (Some(owner), Some(name)) = (
+            env.var("CIRCLE_PROJECT_USERNAME"),
+            env.var("CIRCLE_PROJECT_REPONAME"),
+        ) {
+            context.repo_owner = Some(owner);
+            context.repo_name = Some(name);
+        }
+        context.git_ref = env.var("CIRCLE_BRANCH");
+        context.sha = env.var("CIRCLE_SHA1");
+        context.pr_number = parse_pr_number(env.var("CIRCLE_PR_NUMBER"))
+            .or_else(|| pr_number_from_url(env.var("CIRCLE_PULL_REQUEST")));
+        Some(context)
+    }
+}
+
+/// Travis CI, see https://docs.travis-ci.com/user/environment-variables/
+pub struct Travis;
+
+impl CiProvider for Travis {
+    fn name(&self) -> &'static str {
+        "Travis CI"
+    }
+
+    fn detect(&self, env: &dyn Environment) -> Option<CiContext> {
+        env.var("TRAVIS")?;
+        let mut context = CiContext::new(self.name()).with_slug(env.var("TRAVIS_REPO_SLUG"));
+        context.git_ref = env
+            .var("TRAVIS_PULL_REQUEST_BRANCH")
+            .or_else(|| env.var("TRAVIS_BRANCH"));
+        context.sha = env
+            .var("TRAVIS_PULL_REQUEST_SHA")
+            .or_else(|| env.var("TRAVIS_COMMIT"));
+        context.pr_number = parse_pr_number(env.var("TRAVIS_PULL_REQUEST"));
+        Some(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_no_ci_detected() {
+        let env: HashMap<&str, &str> = HashMap::new();
+        assert_eq!(detect_context(&env), None);
+    }
+
+    #[test]
+    fn test_github_actions() {
+        let env: HashMap<&str, &str> = vec![
+            ("GITHUB_ACTIONS", "true"),
+            ("GITHUB_REPOSITORY", "thibaultdelor/GithubPRCommentator"),
+            ("GITHUB_API_URL", "https://my.github.internal/api/v3"),
+            ("GITHUB_REF", "refs/pull/12/merge"),
+            ("GITHUB_SHA", "4b825dc642cb6eb9a060e54bf8d69288fbee4904"),
+        ]
+        .into_iter()
+        .collect();
+        let context = detect_context(&env).unwrap();
+        assert_eq!(context.provider, "GitHub Actions");
+        assert_eq!(
+            context.api_url,
+            Some(Url::from_str("https://my.github.internal/api/v3/").unwrap())
+        );
+        assert_eq!(context.repo_owner.as_deref(), Some("thibaultdelor"));
+        assert_eq!(context.repo_name.as_deref(), Some("GithubPRCommentator"));
+        assert_eq!(context.git_ref.as_deref(), Some("refs/pull/12/merge"));
+        assert_eq!(context.pr_number, None);
+    }
+
+    #[test]
+    fn test_github_event() {
+        assert_eq!(
+            pr_number_from_github_event(r#"{"action": "opened", "pull_request": {"number": 3}}"#)
+                .unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            pr_number_from_github_event(
+                r#"{"issue": {"number": 4, "pull_request": {"url": "https://api.github.com"}}}"#
+            )
+            .unwrap(),
+            Some(4)
+        );
+        assert_eq!(
+            pr_number_from_github_event(r#"{"issue": {"number": 4}}"#).unwrap(),
+            None
+        );
+        assert_eq!(
+            pr_number_from_github_event(r#"{"ref": "refs/heads/master"}"#).unwrap(),
+            None
+        );
+        assert!(pr_number_from_github_event("not json").is_err());
+    }
+
+    #[test]
+    fn test_jenkins() {
+        let env: HashMap<&str, &str> = vec![
+            ("JENKINS_URL", "https://jenkins.internal/"),
+            (
+                "GIT_URL",
+                "git@my.github.internal:thibaultdelor/GithubPRCommentator.git",
+            ),
+            ("CHANGE_ID", "7"),
+            ("CHANGE_BRANCH", "my_branch"),
+        ]
+        .into_iter()
+        .collect();
+        let context = detect_context(&env).unwrap();
+        assert_eq!(context.provider, "Jenkins");
+        assert_eq!(
+            context.api_url,
+            Some(Url::from_str("https://my.github.internal/api/v3/").unwrap())
+        );
+        assert_eq!(context.repo_owner.as_deref(), Some("thibaultdelor"));
+        assert_eq!(context.repo_name.as_deref(), Some("GithubPRCommentator"));
+        assert_eq!(context.git_ref.as_deref(), Some("my_branch"));
+        assert_eq!(context.pr_number, Some(7));
+    }
+
+    #[test]
+    fn test_jenkins_branch_without_remote() {
+        let env: HashMap<&str, &str> = vec![
+            ("JENKINS_URL", "https://jenkins.internal/"),
+            ("GIT_BRANCH", "origin/feature/foo"),
+        ]
+        .into_iter()
+        .collect();
+        let context = detect_context(&env).unwrap();
+        assert_eq!(context.git_ref.as_deref(), Some("feature/foo"));
+    }
+
+    #[test]
+    fn test_buildkite_not_a_pr() {
+        let env: HashMap<&str, &str> = vec![
+            ("BUILDKITE", "true"),
+            (
+                "BUILDKITE_REPO",
+                "https://github.com/thibaultdelor/GithubPRCommentator.git",
+            ),
+            ("BUILDKITE_BRANCH", "my_branch"),
+            ("BUILDKITE_COMMIT", "HEAD"),
+            ("BUILDKITE_PULL_REQUEST", "false"),
+        ]
+        .into_iter()
+        .collect();
+        let context = detect_context(&env).unwrap();
+        assert_eq!(context.provider, "Buildkite");
+        assert_eq!(
+            context.api_url,
+            Some(Url::from_str("https://api.github.com/").unwrap())
+        );
+        assert_eq!(context.git_ref.as_deref(), Some("my_branch"));
+        assert_eq!(context.sha, None);
+        assert_eq!(context.pr_number, None);
+    }
+
+    #[test]
+    fn test_circleci() {
+        let env: HashMap<&str, &str> = vec![
+            ("CIRCLECI", "true"),
+            ("CIRCLE_PROJECT_USERNAME", "thibaultdelor"),
+            ("CIRCLE_PROJECT_REPONAME", "GithubPRCommentator"),
+            ("CIRCLE_BRANCH", "my_branch"),
+            (
+                "CIRCLE_PULL_REQUEST",
+                "https://github.com/thibaultdelor/GithubPRCommentator/pull/42",
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let context = detect_context(&env).unwrap();
+        assert_eq!(context.repo_owner.as_deref(), Some("thibaultdelor"));
+        assert_eq!(context.pr_number, Some(42));
+    }
+
+    #[test]
+    fn test_travis_and_gitlab() {
+        let travis: HashMap<&str, &str> = vec![
+            ("TRAVIS", "true"),
+            ("TRAVIS_REPO_SLUG", "thibaultdelor/GithubPRCommentator"),
+            ("TRAVIS_BRANCH", "master"),
+            ("TRAVIS_PULL_REQUEST_BRANCH", "my_branch"),
+            ("TRAVIS_PULL_REQUEST", "5"),
+        ]
+        .into_iter()
+        .collect();
+        let context = detect_context(&travis).unwrap();
+        assert_eq!(context.provider, "Travis CI");
+        assert_eq!(context.repo_name.as_deref(), Some("GithubPRCommentator"));
+        assert_eq!(context.git_ref.as_deref(), Some("my_branch"));
+        assert_eq!(context.pr_number, Some(5));
+
+        let gitlab: HashMap<&str, &str> = vec![
+            ("GITLAB_CI", "true"),
+            (
+                "CI_EXTERNAL_PULL_REQUEST_TARGET_REPOSITORY",
+                "thibaultdelor/GithubPRCommentator",
+            ),
+            ("CI_EXTERNAL_PULL_REQUEST_IID", "6"),
+            ("CI_COMMIT_REF_NAME", "my_branch"),
+        ]
+        .into_iter()
+        .collect();
+        let context = detect_context(&gitlab).unwrap();
+        assert_eq!(context.provider, "GitLab CI");
+        assert_eq!(context.repo_owner.as_deref(), Some("thibaultdelor"));
+        assert_eq!(context.pr_number, Some(6));
+    }
+}
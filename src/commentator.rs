@@ -0,0 +1,2438 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use strum_macros::{Display, EnumString, EnumVariantNames};
+
+use crate::forge::Forge;
+use crate::github::checks::{
+    Annotation, CheckRun, CheckRunConclusion, CheckRunReport, CheckRunStatus,
+};
+use crate::github::limit::{split_markdown, truncate_markdown};
+use crate::github::metadata::{
+    content_hash, upsert_section, CommentMetadata, GistReference, HtmlCommentMetadataHandler,
+    Section,
+};
+use crate::github::review::{review_body, split_findings, Finding, Review};
+use crate::github::status::{CommitStatus, StatusCreateRequest};
+use crate::github::{GithubAPI, IssueComment, PrStatePreference, PullRequest};
+use crate::source::CommentSource;
+
+/// Identify the comments generated by this tool
+const METADATA_ID: &str = "pr_commentator : ";
+/// Identify the comments shared between runs, made of sections
+const SECTIONS_METADATA_ID: &str = "pr_commentator_sections : ";
+/// Maximum number of times a section is written when concurrent runs overwrite it
+const SECTION_MAX_ATTEMPTS: u32 = 5;
+/// Time given to concurrent runs to write the shared comment before checking the section
+const SECTION_SETTLE_DELAY: Duration = Duration::from_secs(2);
+/// The characters kept for the number of a part, e.g. "\n\n_Part 2/3_"
+const PART_FOOTER_LENGTH: usize = 32;
+/// The characters kept for the url of a gist in the metadata and the notice
+const GIST_URL_MAX_LENGTH: usize = 256;
+const GIST_FILE_NAME: &str = "comment.md";
+/// The link shown until the gist is created, e.g. when running dry
+const NEW_GIST_URL: &str = "<new gist>";
+
+/// Define the behaviour when writing the comment on the PR
+#[derive(Debug, EnumString, EnumVariantNames, Display, PartialEq, Eq, Clone, Copy, Default)]
+pub enum CommentOverwriteMode {
+    /// Dont check for existing generated comment, just append
+    Never,
+    /// Always overwrite previous generated comment
+    #[default]
+    Always,
+    /// Overwrite only if provided identifier matches
+    UsingIdentifier,
+    /// Always post a new comment and minimize the previous generated comments as outdated,
+    /// only the ones with the same identifier if provided
+    Minimize,
+}
+
+/// Define how a comment longer than `Forge::max_comment_length` is written
+#[derive(Debug, EnumString, EnumVariantNames, Display, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OversizeStrategy {
+    /// Keep the start of the comment with a notice
+    #[default]
+    Truncate,
+    /// Write the comment in several numbered comments, overwritten together
+    Split,
+    /// Keep the start of the comment with a link to a secret gist holding the rest. Gists can't be
+    /// created with an app token, nor on GitLab.
+    Gist,
+}
+
+/// Define how the PR to comment on is identified
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PrReference {
+    /// The PR number, already known (e.g. detected from the CI environment)
+    Number(u64),
+    /// A git reference, either a branch or a PR reference (e.g. `refs/pull/1/head` or
+    /// `refs/merge-requests/1/head`)
+    GitRef(String),
+    /// A commit sha, resolved through the PRs associated with the commit. Only on Github.
+    CommitSha {
+        sha: String,
+        state_preference: PrStatePreference,
+    },
+}
+
+impl PrReference {
+    pub fn find_pr(&self, forge: &dyn Forge, repo_owner: &str, repo_name: &str) -> Result<u64> {
+        match self {
+            PrReference::Number(number) => Ok(*number),
+            PrReference::GitRef(git_ref) => forge.find_pr_for_ref(repo_owner, repo_name, git_ref),
+            PrReference::CommitSha {
+                sha,
+                state_preference,
+            } => github_only(forge, "Finding the PR of a commit")?.find_pr_for_sha(
+                repo_owner,
+                repo_name,
+                sha,
+                *state_preference,
+            ),
+        }
+    }
+}
+
+/// What to do on the PR
+#[derive(Debug)]
+pub enum Operation {
+    /// Create a comment, or overwrite the previous one depending on the overwrite mode
+    Comment(CommentSource),
+    /// Delete the previously generated comments matching the overwrite rules
+    Delete,
+    /// Post a review with the findings as inline comments, superseding the previous generated
+    /// reviews depending on the overwrite mode
+    Review {
+        findings: Vec<Finding>,
+        summary: Option<CommentSource>,
+    },
+    /// Replace a named section of a comment shared with other runs, leaving the others as is
+    Section { name: String, source: CommentSource },
+    /// Publish a check run on the PR head commit, the content being the summary, updating the
+    /// latest check run with the same name unless the overwrite mode is `Never`
+    CheckRun {
+        name: String,
+        status: CheckRunStatus,
+        conclusion: Option<CheckRunConclusion>,
+        title: Option<String>,
+        annotations: Vec<Annotation>,
+        summary: CommentSource,
+    },
+    /// Only set the commit status
+    Status,
+}
+
+/// A comment written on the PR
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PostedComment {
+    pub id: u64,
+    pub html_url: String,
+}
+
+impl From<&IssueComment> for PostedComment {
+    fn from(comment: &IssueComment) -> PostedComment {
+        PostedComment {
+            id: comment.id,
+            html_url: comment.html_url.clone(),
+        }
+    }
+}
+
+/// What would be done to the generated comment
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum PlannedAction {
+    /// Create a comment with this body, plus a nonce in its metadata
+    Create { body: String },
+    /// Replace the body of the generated comment
+    Edit {
+        comment: PostedComment,
+        current_body: String,
+        body: String,
+    },
+    /// The generated comment already has this content
+    Skip(PostedComment),
+}
+
+impl fmt::Display for PlannedAction {
+    /// The action, with the unified diff of the body
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlannedAction::Create { body } => write!(
+                f,
+                "Would create a comment\n{}",
+                diffy::create_patch("", body)
+            ),
+            PlannedAction::Edit {
+                comment,
+                current_body,
+                body,
+            } => write!(
+                f,
+                "Would edit comment {} ({})\n{}",
+                comment.id,
+                comment.html_url,
+                diffy::create_patch(current_body, body)
+            ),
+            PlannedAction::Skip(comment) => write!(
+                f,
+                "Would skip comment {} ({}), already up to date",
+                comment.id, comment.html_url
+            ),
+        }
+    }
+}
+
+/// The content of a part of a comment with its metadata
+type PartContent = (String, CommentMetadata);
+
+/// The gist holding the end of a comment too long
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PlannedGist {
+    /// The gist to update, a gist is created if none
+    pub existing: Option<GistReference>,
+    pub content: String,
+}
+
+impl fmt::Display for PlannedGist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.existing {
+            Some(gist) => write!(f, "Would update gist {}", gist.html_url)?,
+            None => write!(f, "Would create a gist")?,
+        }
+        write!(
+            f,
+            " with the {} characters over the limit",
+            self.content.chars().count()
+        )
+    }
+}
+
+/// What `upsert` would do
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UpsertPlan {
+    pub pr_number: u64,
+    pub action: PlannedAction,
+    /// The actions on the next parts, when the comment is split
+    pub parts: Vec<PlannedAction>,
+    /// The parts of the previous comment not needed anymore
+    pub to_delete: Vec<PostedComment>,
+    pub gist: Option<PlannedGist>,
+    /// The previous generated comments to minimize as outdated
+    pub to_minimize: Vec<IssueComment>,
+    /// The content and the metadata of each part, to create them
+    contents: Vec<PartContent>,
+    /// Whether several comments were found for a part, to reconcile once written
+    has_duplicates: bool,
+}
+
+impl fmt::Display for UpsertPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(gist) = &self.gist {
+            writeln!(f, "{}", gist)?;
+        }
+        write!(f, "PR#{}: {}", self.pr_number, self.action)?;
+        for (index, part) in self.parts.iter().enumerate() {
+            write!(f, "\nPart {}: {}", index + 2, part)?;
+        }
+        for comment in &self.to_delete {
+            write!(
+                f,
+                "\nWould delete comment {} ({})",
+                comment.id, comment.html_url
+            )?;
+        }
+        for comment in &self.to_minimize {
+            write!(
+                f,
+                "\nWould minimize comment {} ({})",
+                comment.id, comment.html_url
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What `Commentator::run` would do when running dry
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Plan {
+    /// Write the comment, or the section of the shared comment
+    Upsert(UpsertPlan),
+    Status {
+        sha: String,
+        status: StatusCreateRequest,
+    },
+    CheckRun {
+        name: String,
+        /// The id of the check run to update
+        existing: Option<u64>,
+        annotations: usize,
+    },
+    Review {
+        pr_number: u64,
+        inline_comments: usize,
+        /// The ids of the previous reviews to supersede
+        superseded: Vec<u64>,
+    },
+    Delete {
+        pr_number: u64,
+        comments: Vec<PostedComment>,
+    },
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Plan::Upsert(plan) => write!(f, "{}", plan),
+            Plan::Status { sha, status } => write!(
+                f,
+                "Would set status {} of {} on commit {}",
+                status.state, status.context, sha
+            ),
+            Plan::CheckRun {
+                name,
+                existing: Some(id),
+                annotations,
+            } => write!(
+                f,
+                "Would update check run {} ({}) with {} annotations",
+                id, name, annotations
+            ),
+            Plan::CheckRun {
+                name,
+                existing: None,
+                annotations,
+            } => write!(
+                f,
+                "Would create check run {} with {} annotations",
+                name, annotations
+            ),
+            Plan::Review {
+                pr_number,
+                inline_comments,
+                superseded,
+            } => {
+                write!(
+                    f,
+                    "Would post a review with {} inline comments on PR#{}",
+                    inline_comments, pr_number
+                )?;
+                for review_id in superseded {
+                    write!(f, "\nWould supersede review {}", review_id)?;
+                }
+                Ok(())
+            }
+            Plan::Delete {
+                pr_number,
+                comments,
+            } => {
+                if comments.is_empty() {
+                    return write!(f, "No generated comment to delete on PR#{}", pr_number);
+                }
+                let lines: Vec<String> = comments
+                    .iter()
+                    .map(|comment| {
+                        format!("Would delete comment {} ({})", comment.id, comment.html_url)
+                    })
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+        }
+    }
+}
+
+/// What happened to the generated comment
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CommentOutcome {
+    Created(PostedComment),
+    Updated(PostedComment),
+    /// The generated comment already had this content
+    Skipped(PostedComment),
+    /// Nothing was written, as running dry
+    Planned(Box<UpsertPlan>),
+}
+
+impl CommentOutcome {
+    /// The comment written, `None` when running dry and the comment would be created
+    pub fn comment(&self) -> Option<&PostedComment> {
+        match self {
+            CommentOutcome::Created(comment)
+            | CommentOutcome::Updated(comment)
+            | CommentOutcome::Skipped(comment) => Some(comment),
+            CommentOutcome::Planned(plan) => match &plan.action {
+                PlannedAction::Create { .. } => None,
+                PlannedAction::Edit { comment, .. } | PlannedAction::Skip(comment) => Some(comment),
+            },
+        }
+    }
+}
+
+/// The Github api, failing on the other forges as the feature is only available on Github
+fn github_only<'a>(forge: &'a dyn Forge, feature: &str) -> Result<&'a GithubAPI> {
+    forge.github().ok_or_else(|| {
+        anyhow!(
+            "{} is only supported on Github, not on {}",
+            feature,
+            forge.kind()
+        )
+    })
+}
+
+/// The part of a comment split in several ones, 1 if not split
+fn part_number(metadata_handler: &HtmlCommentMetadataHandler, comment: &IssueComment) -> u32 {
+    match metadata_handler.get_metadata_from_comment::<CommentMetadata>(&comment.body) {
+        Some(Ok(metadata)) => metadata.part_number(),
+        _ => 1,
+    }
+}
+
+/// Whether the comment was generated by this tool and matches the overwrite rules
+fn is_matching_comment(
+    metadata_handler: &HtmlCommentMetadataHandler,
+    overwrite_mode: CommentOverwriteMode,
+    overwrite_identifier: &Option<String>,
+    body: &str,
+) -> bool {
+    match metadata_handler.get_metadata_from_comment::<CommentMetadata>(body) {
+        None => false,
+        Some(Ok(CommentMetadata { identifier, .. })) => match overwrite_mode {
+            CommentOverwriteMode::Never => false,
+            CommentOverwriteMode::Always => true,
+            CommentOverwriteMode::UsingIdentifier => *overwrite_identifier == identifier,
+            CommentOverwriteMode::Minimize => {
+                overwrite_identifier.is_none() || *overwrite_identifier == identifier
+            }
+        },
+        Some(Err(e)) => {
+            warn!("Failed to parse metadata of a comment : {:?}\n{}", body, e);
+            false
+        }
+    }
+}
+
+/// Comment on a PR, or a GitLab MR, e.g.
+/// `Commentator::new(api).repo("org", "repo").pr_for_ref("refs/heads/my_branch").upsert(body, mode)`
+#[derive(Debug)]
+pub struct Commentator {
+    forge: Box<dyn Forge>,
+    repo: Option<(String, String)>,
+    pr_reference: Option<PrReference>,
+    overwrite_mode: CommentOverwriteMode,
+    overwrite_identifier: Option<String>,
+    /// Set alongside the operation
+    commit_status: Option<StatusCreateRequest>,
+    /// Plan what would be written instead of writing it
+    dry_run: bool,
+    oversize_strategy: OversizeStrategy,
+    /// Wait before checking that a section wasn't overwritten by a concurrent run
+    section_settle_delay: Duration,
+}
+
+impl Commentator {
+    /// Comment through a `GithubAPI` or a `gitlab::GitlabAPI`
+    pub fn new<F: Forge + 'static>(forge: F) -> Commentator {
+        Commentator {
+            forge: Box::new(forge),
+            repo: None,
+            pr_reference: None,
+            overwrite_mode: CommentOverwriteMode::default(),
+            overwrite_identifier: None,
+            commit_status: None,
+            dry_run: false,
+            oversize_strategy: OversizeStrategy::default(),
+            section_settle_delay: SECTION_SETTLE_DELAY,
+        }
+    }
+
+    pub fn repo<O: Into<String>, R: Into<String>>(mut self, owner: O, name: R) -> Commentator {
+        self.repo = Some((owner.into(), name.into()));
+        self
+    }
+
+    pub fn pr_reference(mut self, pr_reference: PrReference) -> Commentator {
+        self.pr_reference = Some(pr_reference);
+        self
+    }
+
+    pub fn pr_number(self, number: u64) -> Commentator {
+        self.pr_reference(PrReference::Number(number))
+    }
+
+    pub fn pr_for_ref<T: Into<String>>(self, git_ref: T) -> Commentator {
+        self.pr_reference(PrReference::GitRef(git_ref.into()))
+    }
+
+    pub fn pr_for_sha<T: Into<String>>(
+        self,
+        sha: T,
+        state_preference: PrStatePreference,
+    ) -> Commentator {
+        self.pr_reference(PrReference::CommitSha {
+            sha: sha.into(),
+            state_preference,
+        })
+    }
+
+    /// The overwrite mode of the operations other than `upsert`
+    pub fn overwrite_mode(mut self, overwrite_mode: CommentOverwriteMode) -> Commentator {
+        self.overwrite_mode = overwrite_mode;
+        self
+    }
+
+    pub fn overwrite_identifier<T: Into<String>>(mut self, identifier: T) -> Commentator {
+        self.overwrite_identifier = Some(identifier.into());
+        self
+    }
+
+    /// Set this commit status when running an operation
+    pub fn commit_status(mut self, status: StatusCreateRequest) -> Commentator {
+        self.commit_status = Some(status);
+        self
+    }
+
+    /// Only read from Github, planning the actions that would be taken with the diff of the
+    /// comments instead of writing them, see `Plan`
+    pub fn dry_run(mut self, dry_run: bool) -> Commentator {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// How `upsert` writes a comment longer than `Forge::max_comment_length`
+    pub fn oversize_strategy(mut self, oversize_strategy: OversizeStrategy) -> Commentator {
+        self.oversize_strategy = oversize_strategy;
+        self
+    }
+
+    pub fn section_settle_delay(mut self, delay: Duration) -> Commentator {
+        self.section_settle_delay = delay;
+        self
+    }
+
+    pub fn forge(&self) -> &dyn Forge {
+        self.forge.as_ref()
+    }
+
+    fn github(&self, feature: &str) -> Result<&GithubAPI> {
+        github_only(self.forge.as_ref(), feature)
+    }
+
+    fn repo_owner(&self) -> &str {
+        self.repo
+            .as_ref()
+            .map(|(owner, _)| owner.as_str())
+            .unwrap_or_default()
+    }
+
+    fn repo_name(&self) -> &str {
+        self.repo
+            .as_ref()
+            .map(|(_, name)| name.as_str())
+            .unwrap_or_default()
+    }
+
+    /// Resolve the PR number from the PR reference
+    pub fn find_pr(&self) -> Result<u64> {
+        if self.repo.is_none() {
+            return Err(anyhow!("Missing repo to comment on"));
+        }
+        debug!("Determining PR number");
+        self.pr_reference
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing reference to retrieve the PR"))?
+            .find_pr(self.forge.as_ref(), self.repo_owner(), self.repo_name())
+    }
+
+    /// The metadata of the comments with this content, without nonce as they are only needed for
+    /// creation
+    fn metadata(&self, content: &str) -> CommentMetadata {
+        CommentMetadata {
+            identifier: self.overwrite_identifier.clone(),
+            ..CommentMetadata::default()
+        }
+        .with_hash(content)
+    }
+
+    /// List the generated comments of the PR matching the overwrite rules, oldest first
+    fn find_matching_comments(
+        &self,
+        pr_number: u64,
+        metadata_handler: &HtmlCommentMetadataHandler,
+        overwrite_mode: CommentOverwriteMode,
+    ) -> Result<Vec<IssueComment>> {
+        debug!("Searching generated comments on PR#{}", pr_number);
+        self.forge
+            .list_comments(self.repo_owner(), self.repo_name(), pr_number)
+            .map(|comments| {
+                comments
+                    .into_iter()
+                    .filter(|c| {
+                        is_matching_comment(
+                            metadata_handler,
+                            overwrite_mode,
+                            &self.overwrite_identifier,
+                            &c.body,
+                        )
+                    })
+                    .collect()
+            })
+    }
+
+    /// Run the operation as configured from the command line. When running dry nothing is
+    /// written and what would be done is returned instead.
+    pub fn run(&self, operation: &mut Operation) -> Result<Vec<Plan>> {
+        let mut plans = Vec::new();
+        // A status on a given sha doesn't need the PR
+        let status_sha = match (&self.commit_status, &self.pr_reference) {
+            (Some(_), Some(PrReference::CommitSha { sha, .. })) => Some(sha.clone()),
+            _ => None,
+        };
+        if let (Operation::Status, Some(sha)) = (&operation, &status_sha) {
+            self.run_commit_status(sha, &mut plans)?;
+            return Ok(plans);
+        }
+
+        let pr_number = self.find_pr()?;
+        let needs_pull_request = match &operation {
+            _ if self.commit_status.is_some() && status_sha.is_none() => true,
+            Operation::CheckRun { .. } => true,
+            Operation::Comment(source)
+            | Operation::Section { source, .. }
+            | Operation::Review {
+                summary: Some(source),
+                ..
+            } => source.needs_pull_request(),
+            _ => false,
+        };
+        let pull_request = if needs_pull_request {
+            debug!("Fetching PR#{} details", pr_number);
+            Some(self.github("Fetching the PR details")?.get_pull_request(
+                self.repo_owner(),
+                self.repo_name(),
+                pr_number,
+            )?)
+        } else {
+            None
+        };
+
+        if self.commit_status.is_some() {
+            let sha = match (status_sha, &pull_request) {
+                (Some(sha), _) => sha,
+                (None, Some(pull_request)) => pull_request.head.sha.clone(),
+                (None, None) => return Err(anyhow!("PR details are required to set a status")),
+            };
+            self.run_commit_status(&sha, &mut plans)?;
+        }
+
+        match operation {
+            Operation::Status => {}
+            Operation::Delete => {
+                let comments = self.delete_on(pr_number)?;
+                if self.dry_run {
+                    plans.push(Plan::Delete {
+                        pr_number,
+                        comments,
+                    });
+                }
+            }
+            Operation::Review { findings, summary } => {
+                let summary = match summary {
+                    Some(summary) => self
+                        .retrieve(summary, pull_request.as_ref())
+                        .context("Failed to read review summary")?,
+                    None => String::new(),
+                };
+                if self.dry_run {
+                    plans.push(self.plan_review_on(pr_number, &summary, findings.clone())?);
+                } else {
+                    self.post_review_on(pr_number, &summary, findings.clone())?;
+                }
+            }
+            Operation::Section { name, source } => {
+                debug!("Evaluating section content");
+                let section = Section {
+                    name: name.clone(),
+                    content: self
+                        .retrieve(source, pull_request.as_ref())
+                        .context("Failed to read section")?,
+                };
+                if let CommentOutcome::Planned(plan) = self.upsert_section_on(pr_number, section)? {
+                    plans.push(Plan::Upsert(*plan));
+                }
+            }
+            Operation::CheckRun {
+                name,
+                status,
+                conclusion,
+                title,
+                annotations,
+                summary,
+            } => {
+                let pull_request = pull_request
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("PR details are required to publish a check run"))?;
+                let summary = self
+                    .retrieve(summary, Some(pull_request))
+                    .context("Failed to read check run summary")?;
+                let report = CheckRunReport {
+                    name: name.clone(),
+                    head_sha: pull_request.head.sha.clone(),
+                    status: *status,
+                    conclusion: if *status == CheckRunStatus::Completed {
+                        Some(conclusion.unwrap_or(if annotations.is_empty() {
+                            CheckRunConclusion::Success
+                        } else {
+                            CheckRunConclusion::Failure
+                        }))
+                    } else {
+                        None
+                    },
+                    title: title.clone().unwrap_or_else(|| name.clone()),
+                    summary,
+                    annotations: annotations.clone(),
+                };
+                if self.dry_run {
+                    plans.push(self.plan_check_run(&report)?);
+                } else {
+                    self.publish_check_run_on(pr_number, &report)?;
+                }
+            }
+            Operation::Comment(comment_source) => {
+                debug!("Evaluating comment content");
+                let comment = self
+                    .retrieve(comment_source, pull_request.as_ref())
+                    .context("Failed to read comment")?;
+                if let CommentOutcome::Planned(plan) =
+                    self.upsert_on(pr_number, &comment, self.overwrite_mode)?
+                {
+                    plans.push(Plan::Upsert(*plan));
+                }
+            }
+        }
+        Ok(plans)
+    }
+
+    /// Set the commit status, or plan it when running dry
+    fn run_commit_status(&self, sha: &str, plans: &mut Vec<Plan>) -> Result<()> {
+        if self.dry_run {
+            plans.extend(self.plan_commit_status(sha)?);
+        } else {
+            self.post_commit_status(sha)?;
+        }
+        Ok(())
+    }
+
+    /// Read the content, masking the secrets it could contain, e.g. rendered by a template
+    fn retrieve(
+        &self,
+        source: &mut CommentSource,
+        pull_request: Option<&PullRequest>,
+    ) -> Result<String> {
+        source
+            .retrieve(pull_request)
+            .map(|content| self.forge.mask_secrets(&content))
+    }
+
+    /// Create the comment, or overwrite the previous generated one depending on the mode
+    pub fn upsert(
+        &self,
+        comment: &str,
+        overwrite_mode: CommentOverwriteMode,
+    ) -> Result<CommentOutcome> {
+        let pr_number = self.find_pr()?;
+        self.upsert_on(pr_number, comment, overwrite_mode)
+    }
+
+    /// What `upsert` would do, without writing anything
+    pub fn plan_upsert(
+        &self,
+        comment: &str,
+        overwrite_mode: CommentOverwriteMode,
+    ) -> Result<UpsertPlan> {
+        let pr_number = self.find_pr()?;
+        self.plan_upsert_on(pr_number, comment, overwrite_mode)
+    }
+
+    fn plan_upsert_on(
+        &self,
+        pr_number: u64,
+        comment: &str,
+        overwrite_mode: CommentOverwriteMode,
+    ) -> Result<UpsertPlan> {
+        self.plan_upsert_with_gist(pr_number, comment, overwrite_mode, None)
+    }
+
+    /// Plan the upsert, linking to the given gist rather than to the one of the existing comment
+    fn plan_upsert_with_gist(
+        &self,
+        pr_number: u64,
+        comment: &str,
+        overwrite_mode: CommentOverwriteMode,
+        gist: Option<GistReference>,
+    ) -> Result<UpsertPlan> {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: METADATA_ID.to_string(),
+        };
+        let to_minimize = if overwrite_mode == CommentOverwriteMode::Minimize {
+            self.github("Minimizing comments")?;
+            self.find_matching_comments(pr_number, &metadata_handler, overwrite_mode)?
+        } else {
+            Vec::new()
+        };
+
+        let mut existing_parts: BTreeMap<u32, IssueComment> = BTreeMap::new();
+        let mut has_duplicates = false;
+        if overwrite_mode != CommentOverwriteMode::Never
+            && overwrite_mode != CommentOverwriteMode::Minimize
+        {
+            for existing in
+                self.find_matching_comments(pr_number, &metadata_handler, overwrite_mode)?
+            {
+                // The newest one, its content is moved to the oldest one when reconciling
+                has_duplicates |= existing_parts
+                    .insert(part_number(&metadata_handler, &existing), existing)
+                    .is_some();
+            }
+        }
+        let existing_gist = gist.or_else(|| {
+            existing_parts
+                .get(&1)
+                .and_then(|first| {
+                    metadata_handler.get_metadata_from_comment::<CommentMetadata>(&first.body)
+                })
+                .and_then(|metadata| metadata.ok())
+                .and_then(|metadata| metadata.gist)
+        });
+
+        let (contents, planned_gist) =
+            self.fit_comment(&metadata_handler, comment, existing_gist)?;
+        let part_count = contents.len() as u32;
+        let mut actions = Vec::new();
+        for (index, (content, metadata)) in contents.iter().enumerate() {
+            let existing = existing_parts.remove(&(index as u32 + 1));
+            actions.push(self.plan_action(&metadata_handler, existing, content, metadata)?);
+        }
+        let mut actions = actions.into_iter();
+        Ok(UpsertPlan {
+            pr_number,
+            action: actions.next().unwrap(),
+            parts: actions.collect(),
+            to_delete: existing_parts
+                .iter()
+                .filter(|(part, _)| **part > part_count)
+                .map(|(_, comment)| comment.into())
+                .collect(),
+            gist: planned_gist,
+            to_minimize,
+            contents,
+            has_duplicates,
+        })
+    }
+
+    /// The content of each part of the comment with its metadata, fitting in
+    /// `Forge::max_comment_length` depending on the oversize strategy, and the gist to write if any
+    fn fit_comment(
+        &self,
+        metadata_handler: &HtmlCommentMetadataHandler,
+        comment: &str,
+        existing_gist: Option<GistReference>,
+    ) -> Result<(Vec<PartContent>, Option<PlannedGist>)> {
+        // The longest metadata, with a nonce, a part number and a gist
+        let longest_metadata = CommentMetadata {
+            part: Some(u32::MAX),
+            gist: Some(GistReference {
+                id: String::new(),
+                html_url: "x".repeat(GIST_URL_MAX_LENGTH),
+                hash: content_hash(""),
+            }),
+            ..self.metadata("").with_nonce()
+        };
+        let max_comment_length = self.forge.max_comment_length();
+        let max_length = max_comment_length.saturating_sub(
+            metadata_handler
+                .add_metadata_to_comment(&"", &longest_metadata)?
+                .chars()
+                .count(),
+        );
+        if comment.chars().count() <= max_length {
+            return Ok((vec![(comment.to_owned(), self.metadata(comment))], None));
+        }
+
+        match self.oversize_strategy {
+            OversizeStrategy::Truncate => {
+                warn!(
+                    "The comment is longer than {} characters, truncating it",
+                    max_comment_length
+                );
+                let notice = format!(
+                    "\n\n---\n_The comment was truncated as it is longer than the {} characters \
+                     allowed by {}._",
+                    max_comment_length,
+                    self.forge.kind()
+                );
+                let content = truncate_markdown(comment, max_length, &notice);
+                let metadata = self.metadata(&content);
+                Ok((vec![(content, metadata)], None))
+            }
+            OversizeStrategy::Split => {
+                let mut heads = Vec::new();
+                let mut rest = Some(comment.to_owned());
+                while let Some(text) = rest {
+                    let (head, tail) = split_markdown(&text, max_length - PART_FOOTER_LENGTH);
+                    heads.push(head);
+                    rest = tail;
+                }
+                warn!(
+                    "The comment is longer than {} characters, splitting it in {} parts",
+                    max_comment_length,
+                    heads.len()
+                );
+                let part_count = heads.len();
+                Ok((
+                    heads
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, head)| {
+                            let content =
+                                format!("{}\n\n_Part {}/{}_", head, index + 1, part_count);
+                            let metadata = CommentMetadata {
+                                part: if index == 0 {
+                                    None
+                                } else {
+                                    Some(index as u32 + 1)
+                                },
+                                ..self.metadata(&content)
+                            };
+                            (content, metadata)
+                        })
+                        .collect(),
+                    None,
+                ))
+            }
+            OversizeStrategy::Gist => {
+                self.github("Moving the end of a comment to a gist")?;
+                warn!(
+                    "The comment is longer than {} characters, moving the end in a gist",
+                    max_comment_length
+                );
+                let url = existing_gist
+                    .as_ref()
+                    .map(|gist| gist.html_url.as_str())
+                    .unwrap_or(NEW_GIST_URL);
+                let notice = format!(
+                    "\n\n---\n_The end of the comment is in [this gist]({}) as it is longer than \
+                     the {} characters allowed by Github._",
+                    url, max_comment_length
+                );
+                let (head, rest) =
+                    split_markdown(comment, max_length.saturating_sub(notice.chars().count()));
+                let rest = rest.unwrap_or_default();
+                let content = format!("{}{}", head, notice);
+                let hash = content_hash(&rest);
+                let planned_gist = match &existing_gist {
+                    Some(gist) if gist.hash == hash => None,
+                    _ => Some(PlannedGist {
+                        existing: existing_gist.clone(),
+                        content: rest,
+                    }),
+                };
+                let metadata = CommentMetadata {
+                    gist: existing_gist.map(|gist| GistReference { hash, ..gist }),
+                    ..self.metadata(&content)
+                };
+                Ok((vec![(content, metadata)], planned_gist))
+            }
+        }
+    }
+
+    /// Skip the existing comment if its metadata, holding the hash of its content, is the same,
+    /// edit it otherwise, or create a comment if there is none
+    fn plan_action(
+        &self,
+        metadata_handler: &HtmlCommentMetadataHandler,
+        existing: Option<IssueComment>,
+        content: &str,
+        metadata: &CommentMetadata,
+    ) -> Result<PlannedAction> {
+        let existing = match existing {
+            Some(existing) => existing,
+            None => {
+                return Ok(PlannedAction::Create {
+                    body: metadata_handler
+                        .add_metadata_to_comment(&content, metadata)
+                        .context("Can't add Metadata to comment")?,
+                })
+            }
+        };
+        let existing_metadata =
+            match metadata_handler.get_metadata_from_comment::<CommentMetadata>(&existing.body) {
+                Some(Ok(metadata)) => metadata,
+                _ => CommentMetadata::default(),
+            };
+        // Keep the nonce of the comment, it only matters when creating
+        let metadata = CommentMetadata {
+            nonce: existing_metadata.nonce.clone(),
+            ..metadata.clone()
+        };
+        if metadata == existing_metadata {
+            return Ok(PlannedAction::Skip((&existing).into()));
+        }
+        let body = metadata_handler
+            .add_metadata_to_comment(&content, &metadata)
+            .context("Can't add Metadata to comment")?;
+        Ok(PlannedAction::Edit {
+            comment: (&existing).into(),
+            current_body: existing.body,
+            body,
+        })
+    }
+
+    fn upsert_on(
+        &self,
+        pr_number: u64,
+        comment: &str,
+        overwrite_mode: CommentOverwriteMode,
+    ) -> Result<CommentOutcome> {
+        let mut plan = self.plan_upsert_on(pr_number, comment, overwrite_mode)?;
+        if self.dry_run {
+            return Ok(CommentOutcome::Planned(Box::new(plan)));
+        }
+
+        if let Some(gist) = &plan.gist {
+            let description = format!(
+                "End of the comment on {}/{}#{}",
+                self.repo_owner(),
+                self.repo_name(),
+                pr_number
+            );
+            match &gist.existing {
+                Some(existing) => {
+                    self.github("Updating a gist")?.update_gist(
+                        &existing.id,
+                        GIST_FILE_NAME,
+                        &gist.content,
+                    )?;
+                    info!("Successfully updated gist {}", existing.html_url);
+                }
+                None => {
+                    let created = self.github("Creating a gist")?.create_gist(
+                        &description,
+                        GIST_FILE_NAME,
+                        &gist.content,
+                    )?;
+                    info!("Successfully created gist {}", created.html_url);
+                    // The comment links to the gist, known only now
+                    let reference = GistReference {
+                        id: created.id,
+                        html_url: created.html_url,
+                        hash: content_hash(&gist.content),
+                    };
+                    plan = self.plan_upsert_with_gist(
+                        pr_number,
+                        comment,
+                        overwrite_mode,
+                        Some(reference),
+                    )?;
+                }
+            }
+        }
+
+        debug!("Commenting back to PR#{}", pr_number);
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: METADATA_ID.to_string(),
+        };
+        let (content, metadata) = &plan.contents[0];
+        let outcome = match plan.action {
+            PlannedAction::Skip(existing) => {
+                info!(
+                    "Comment {} on PR#{} is already up to date",
+                    existing.id, pr_number
+                );
+                CommentOutcome::Skipped(existing)
+            }
+            PlannedAction::Edit { comment, body, .. } => {
+                let edited = self
+                    .forge
+                    .edit_comment(
+                        self.repo_owner(),
+                        self.repo_name(),
+                        pr_number,
+                        comment.id,
+                        &body,
+                    )
+                    .context("Failed to edit comment")?;
+                info!("Successfully commented back to PR#{}", pr_number);
+                CommentOutcome::Updated((&edited).into())
+            }
+            PlannedAction::Create { .. } => {
+                let created =
+                    self.create_comment(pr_number, &metadata_handler, content, metadata)?;
+                info!("Successfully commented back to PR#{}", pr_number);
+                CommentOutcome::Created((&created).into())
+            }
+        };
+        let mut is_created = matches!(outcome, CommentOutcome::Created(_));
+
+        for (part, (content, metadata)) in plan.parts.into_iter().zip(&plan.contents[1..]) {
+            match part {
+                PlannedAction::Skip(_) => {}
+                PlannedAction::Edit { comment, body, .. } => {
+                    self.forge
+                        .edit_comment(
+                            self.repo_owner(),
+                            self.repo_name(),
+                            pr_number,
+                            comment.id,
+                            &body,
+                        )
+                        .context("Failed to edit comment")?;
+                }
+                PlannedAction::Create { .. } => {
+                    self.create_comment(pr_number, &metadata_handler, content, metadata)?;
+                    is_created = true;
+                }
+            }
+        }
+        for comment in plan.to_delete {
+            debug!("Deleting unneeded part {} on PR#{}", comment.id, pr_number);
+            self.forge
+                .delete_comment(self.repo_owner(), self.repo_name(), pr_number, comment.id)
+                .with_context(|| format!("Failed to delete comment {}", comment.id))?;
+        }
+
+        // Concurrent runs may have created the same comments, or failed to delete the duplicates
+        let outcome = if (is_created || plan.has_duplicates)
+            && overwrite_mode != CommentOverwriteMode::Never
+            && overwrite_mode != CommentOverwriteMode::Minimize
+        {
+            let kept = self.reconcile_comments(pr_number, &metadata_handler)?;
+            match (outcome, kept) {
+                (CommentOutcome::Created(_), Some(kept)) => CommentOutcome::Created(kept),
+                (CommentOutcome::Updated(_), Some(kept)) => CommentOutcome::Updated(kept),
+                (outcome, _) => outcome,
+            }
+        } else {
+            outcome
+        };
+
+        self.minimize_comments(pr_number, plan.to_minimize)?;
+        Ok(outcome)
+    }
+
+    /// Create the comment with a unique nonce in its metadata, so that a creation failing
+    /// ambiguously is found back instead of being retried into a duplicate
+    fn create_comment(
+        &self,
+        pr_number: u64,
+        metadata_handler: &HtmlCommentMetadataHandler,
+        content: &str,
+        metadata: &CommentMetadata,
+    ) -> Result<IssueComment> {
+        let metadata = metadata.clone().with_nonce();
+        let body = metadata_handler
+            .add_metadata_to_comment(&content, &metadata)
+            .context("Can't add Metadata to comment")?;
+        self.forge.comment(
+            self.repo_owner(),
+            self.repo_name(),
+            pr_number,
+            &body,
+            &|comment| match metadata_handler
+                .get_metadata_from_comment::<CommentMetadata>(&comment.body)
+            {
+                Some(Ok(CommentMetadata { nonce, .. })) => nonce == metadata.nonce,
+                _ => false,
+            },
+        )
+    }
+
+    /// Concurrent runs may have all created a comment. Keep the oldest one of each part with the
+    /// content of the newest one and delete the others, so every run converges on the same
+    /// comments. Only the comments with the same identifier are duplicates, whatever the overwrite
+    /// mode. Return the first part kept if there were duplicates.
+    fn reconcile_comments(
+        &self,
+        pr_number: u64,
+        metadata_handler: &HtmlCommentMetadataHandler,
+    ) -> Result<Option<PostedComment>> {
+        let mut parts: BTreeMap<u32, Vec<IssueComment>> = BTreeMap::new();
+        for comment in self.find_matching_comments(
+            pr_number,
+            metadata_handler,
+            CommentOverwriteMode::UsingIdentifier,
+        )? {
+            parts
+                .entry(part_number(metadata_handler, &comment))
+                .or_default()
+                .push(comment);
+        }
+        let mut kept_first_part = None;
+        for (part, comments) in parts {
+            let kept = self.reconcile_part(pr_number, metadata_handler, part, &comments)?;
+            if part == 1 {
+                kept_first_part = kept;
+            }
+        }
+        Ok(kept_first_part)
+    }
+
+    /// Reconcile the comments of the same part, see `reconcile_comments`.
+    /// The kept comment keeps its own nonce, the other ones belonging to the other runs.
+    fn reconcile_part(
+        &self,
+        pr_number: u64,
+        metadata_handler: &HtmlCommentMetadataHandler,
+        part: u32,
+        comments: &[IssueComment],
+    ) -> Result<Option<PostedComment>> {
+        let (oldest, newest) = match (
+            comments.iter().min_by_key(|c| c.id),
+            comments.iter().max_by_key(|c| c.id),
+        ) {
+            (Some(oldest), Some(newest)) if oldest.id != newest.id => (oldest, newest),
+            _ => return Ok(None),
+        };
+        info!(
+            "Found {} generated comments for part {} on PR#{}, keeping comment {}",
+            comments.len(),
+            part,
+            pr_number,
+            oldest.id
+        );
+        let metadata_of = |comment: &IssueComment| match metadata_handler
+            .get_metadata_from_comment::<CommentMetadata>(&comment.body)
+        {
+            Some(Ok(metadata)) => metadata,
+            _ => CommentMetadata::default(),
+        };
+        let body = metadata_handler
+            .add_metadata_to_comment(
+                &metadata_handler.get_content_from_comment(&newest.body),
+                &CommentMetadata {
+                    nonce: metadata_of(oldest).nonce,
+                    ..metadata_of(newest)
+                },
+            )
+            .context("Can't add Metadata to comment")?;
+        if oldest.body != body {
+            self.forge
+                .edit_comment(
+                    self.repo_owner(),
+                    self.repo_name(),
+                    pr_number,
+                    oldest.id,
+                    &body,
+                )
+                .context("Failed to move the newest content into the oldest comment")?;
+        }
+        for duplicate in comments.iter().filter(|c| c.id != oldest.id) {
+            debug!(
+                "Deleting duplicated comment {} on PR#{}",
+                duplicate.id, pr_number
+            );
+            // A concurrent run may be deleting it as well
+            if let Err(e) = self.forge.delete_comment(
+                self.repo_owner(),
+                self.repo_name(),
+                pr_number,
+                duplicate.id,
+            ) {
+                warn!(
+                    "Failed to delete duplicated comment {} : {:#}",
+                    duplicate.id, e
+                );
+            }
+        }
+        Ok(Some(oldest.into()))
+    }
+
+    fn minimize_comments(&self, pr_number: u64, comments: Vec<IssueComment>) -> Result<()> {
+        if comments.is_empty() {
+            return Ok(());
+        }
+        let api = self.github("Minimizing comments")?;
+        for comment in api.filter_not_minimized(comments)? {
+            debug!("Minimizing comment {} on PR#{}", comment.id, pr_number);
+            api.minimize_comment(&comment)?;
+            info!(
+                "Successfully minimized outdated comment {} on PR#{}",
+                comment.id, pr_number
+            );
+        }
+        Ok(())
+    }
+
+    /// Replace the section of the comment shared with other runs, see `Operation::Section`
+    pub fn upsert_section(&self, section: Section) -> Result<CommentOutcome> {
+        let pr_number = self.find_pr()?;
+        self.upsert_section_on(pr_number, section)
+    }
+
+    /// Write the section in the oldest shared comment, creating it if needed, then check that no
+    /// concurrent run overwrote the section, retrying otherwise.
+    /// The check happens after a delay, giving the concurrent runs which read the comment before
+    /// our write the time to write theirs.
+    fn upsert_section_on(&self, pr_number: u64, section: Section) -> Result<CommentOutcome> {
+        if self.overwrite_mode == CommentOverwriteMode::Never
+            || self.overwrite_mode == CommentOverwriteMode::Minimize
+        {
+            return Err(anyhow!(
+                "Sections can't be used with overwrite mode {}",
+                self.overwrite_mode
+            ));
+        }
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: SECTIONS_METADATA_ID.to_string(),
+        };
+        let mut created_comments: Vec<u64> = Vec::new();
+        for attempt in 1..=SECTION_MAX_ATTEMPTS {
+            let shared_comment = self
+                .find_matching_comments(pr_number, &metadata_handler, self.overwrite_mode)?
+                .into_iter()
+                .next();
+            let mut sections = shared_comment
+                .as_ref()
+                .map(|c| metadata_handler.get_sections_from_comment(&c.body))
+                .unwrap_or_default();
+            upsert_section(&mut sections, section.clone());
+            let content = metadata_handler.add_sections_to_comment(&sections)?;
+            let metadata = self.metadata(&content);
+            let action =
+                self.plan_action(&metadata_handler, shared_comment, &content, &metadata)?;
+            if self.dry_run {
+                return Ok(CommentOutcome::Planned(Box::new(UpsertPlan {
+                    pr_number,
+                    action,
+                    parts: Vec::new(),
+                    to_delete: Vec::new(),
+                    gist: None,
+                    to_minimize: Vec::new(),
+                    contents: vec![(content, metadata)],
+                    has_duplicates: false,
+                })));
+            }
+            match action {
+                // Once a comment was created, still check the duplicates
+                PlannedAction::Skip(shared_comment) if created_comments.is_empty() => {
+                    info!(
+                        "Section {} in comment {} on PR#{} is already up to date",
+                        section.name, shared_comment.id, pr_number
+                    );
+                    return Ok(CommentOutcome::Skipped(shared_comment));
+                }
+                PlannedAction::Skip(_) => {}
+                PlannedAction::Edit { comment, body, .. } => {
+                    debug!(
+                        "Writing section {} in comment {} on PR#{}",
+                        section.name, comment.id, pr_number
+                    );
+                    self.forge
+                        .edit_comment(
+                            self.repo_owner(),
+                            self.repo_name(),
+                            pr_number,
+                            comment.id,
+                            &body,
+                        )
+                        .context("Failed to edit comment")?;
+                }
+                PlannedAction::Create { .. } => {
+                    debug!(
+                        "Creating shared comment with section {} on PR#{}",
+                        section.name, pr_number
+                    );
+                    let created =
+                        self.create_comment(pr_number, &metadata_handler, &content, &metadata)?;
+                    created_comments.push(created.id);
+                }
+            }
+
+            thread::sleep(self.section_settle_delay);
+            let shared_comment = self
+                .find_matching_comments(pr_number, &metadata_handler, self.overwrite_mode)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("The shared comment on PR#{} was deleted", pr_number))?;
+            let is_written = metadata_handler
+                .get_sections_from_comment(&shared_comment.body)
+                .iter()
+                .any(|s| s.name == section.name && s.content == section.content.trim());
+            if is_written {
+                let is_created = created_comments.contains(&shared_comment.id);
+                // A concurrent run created the shared comment first, ours is a duplicate
+                for comment_id in created_comments
+                    .into_iter()
+                    .filter(|id| *id != shared_comment.id)
+                {
+                    debug!("Deleting duplicated shared comment {}", comment_id);
+                    self.forge
+                        .delete_comment(self.repo_owner(), self.repo_name(), pr_number, comment_id)
+                        .with_context(|| format!("Failed to delete comment {}", comment_id))?;
+                }
+                info!(
+                    "Successfully wrote section {} in comment {} on PR#{}",
+                    section.name, shared_comment.id, pr_number
+                );
+                let posted = (&shared_comment).into();
+                return Ok(if is_created {
+                    CommentOutcome::Created(posted)
+                } else {
+                    CommentOutcome::Updated(posted)
+                });
+            }
+            warn!(
+                "Section {} was overwritten by a concurrent run, retrying ({}/{})",
+                section.name, attempt, SECTION_MAX_ATTEMPTS
+            );
+        }
+        Err(anyhow!(
+            "Failed to write section {} on PR#{} after {} attempts",
+            section.name,
+            pr_number,
+            SECTION_MAX_ATTEMPTS
+        ))
+    }
+
+    /// Set the commit status given to the builder on the commit
+    pub fn post_commit_status(&self, sha: &str) -> Result<Option<CommitStatus>> {
+        let status = match &self.commit_status {
+            Some(status) => status,
+            None => return Ok(None),
+        };
+        let api = self.github("Setting a commit status")?;
+        if self.dry_run {
+            return Ok(None);
+        }
+        let created = api.create_status(self.repo_owner(), self.repo_name(), sha, status)?;
+        info!(
+            "Successfully set status {} of {} on commit {}",
+            status.state, status.context, sha
+        );
+        Ok(Some(created))
+    }
+
+    /// What `post_commit_status` would do, `None` without commit status
+    pub fn plan_commit_status(&self, sha: &str) -> Result<Option<Plan>> {
+        match &self.commit_status {
+            Some(status) => {
+                self.github("Setting a commit status")?;
+                Ok(Some(Plan::Status {
+                    sha: sha.to_owned(),
+                    status: status.clone(),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Publish the check run, updating the latest one with the same name unless the overwrite
+    /// mode is `Never`
+    pub fn publish_check_run(&self, report: &CheckRunReport) -> Result<Option<CheckRun>> {
+        let pr_number = self.find_pr()?;
+        self.publish_check_run_on(pr_number, report)
+    }
+
+    fn publish_check_run_on(
+        &self,
+        pr_number: u64,
+        report: &CheckRunReport,
+    ) -> Result<Option<CheckRun>> {
+        let api = self.github("Publishing a check run")?;
+        if self.dry_run {
+            return Ok(None);
+        }
+        let existing_check_run = self.find_check_run(api, report)?;
+        let check_run = api.publish_check_run(
+            self.repo_owner(),
+            self.repo_name(),
+            existing_check_run.as_ref(),
+            report,
+        )?;
+        info!(
+            "Successfully published check run {} with {} annotations for PR#{} : {}",
+            check_run.name,
+            report.annotations.len(),
+            pr_number,
+            check_run.html_url
+        );
+        Ok(Some(check_run))
+    }
+
+    /// What `publish_check_run` would do
+    pub fn plan_check_run(&self, report: &CheckRunReport) -> Result<Plan> {
+        let api = self.github("Publishing a check run")?;
+        let existing_check_run = self.find_check_run(api, report)?;
+        Ok(Plan::CheckRun {
+            name: report.name.clone(),
+            existing: existing_check_run.as_ref().map(|check_run| check_run.id),
+            annotations: report.new_annotations(existing_check_run.as_ref()).len(),
+        })
+    }
+
+    /// The check run to update, the latest one with the same name on the commit
+    fn find_check_run(&self, api: &GithubAPI, report: &CheckRunReport) -> Result<Option<CheckRun>> {
+        if self.overwrite_mode == CommentOverwriteMode::Never {
+            return Ok(None);
+        }
+        Ok(api
+            .find_check_runs(
+                self.repo_owner(),
+                self.repo_name(),
+                &report.head_sha,
+                &report.name,
+            )?
+            .into_iter()
+            .next())
+    }
+
+    /// Post a review with the findings as inline comments, see `Operation::Review`
+    pub fn post_review(&self, summary: &str, findings: Vec<Finding>) -> Result<Option<Review>> {
+        let pr_number = self.find_pr()?;
+        self.post_review_on(pr_number, summary, findings)
+    }
+
+    fn post_review_on(
+        &self,
+        pr_number: u64,
+        summary: &str,
+        findings: Vec<Finding>,
+    ) -> Result<Option<Review>> {
+        let api = self.github("Posting a review")?;
+        if self.dry_run {
+            return Ok(None);
+        }
+        let (previous_reviews, inline, body) = self.prepare_review(pr_number, summary, findings)?;
+        let review = api.create_review(
+            self.repo_owner(),
+            self.repo_name(),
+            pr_number,
+            body,
+            &inline,
+        )?;
+        info!(
+            "Successfully posted review {} with {} inline comments on PR#{}",
+            review.id,
+            inline.len(),
+            pr_number
+        );
+
+        for previous in previous_reviews {
+            self.supersede_review(pr_number, &previous)?;
+        }
+        Ok(Some(review))
+    }
+
+    /// What `post_review` would do
+    pub fn plan_review(&self, summary: &str, findings: Vec<Finding>) -> Result<Plan> {
+        let pr_number = self.find_pr()?;
+        self.plan_review_on(pr_number, summary, findings)
+    }
+
+    fn plan_review_on(
+        &self,
+        pr_number: u64,
+        summary: &str,
+        findings: Vec<Finding>,
+    ) -> Result<Plan> {
+        let (previous_reviews, inline, _) = self.prepare_review(pr_number, summary, findings)?;
+        Ok(Plan::Review {
+            pr_number,
+            inline_comments: inline.len(),
+            superseded: previous_reviews.iter().map(|review| review.id).collect(),
+        })
+    }
+
+    /// The previous generated reviews to supersede, the findings to post inline and the body
+    /// of the review
+    fn prepare_review(
+        &self,
+        pr_number: u64,
+        summary: &str,
+        findings: Vec<Finding>,
+    ) -> Result<(Vec<Review>, Vec<Finding>, String)> {
+        let api = self.github("Posting a review")?;
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: METADATA_ID.to_string(),
+        };
+        let previous_reviews: Vec<Review> = if self.overwrite_mode == CommentOverwriteMode::Never {
+            Vec::new()
+        } else {
+            debug!("Searching generated reviews on PR#{}", pr_number);
+            api.list_reviews(self.repo_owner(), self.repo_name(), pr_number)?
+                .into_iter()
+                .filter(|r| {
+                    is_matching_comment(
+                        &metadata_handler,
+                        self.overwrite_mode,
+                        &self.overwrite_identifier,
+                        r.body.as_deref().unwrap_or_default(),
+                    )
+                })
+                .collect()
+        };
+
+        let files = api.list_pr_files(self.repo_owner(), self.repo_name(), pr_number)?;
+        let (inline, outside_diff) = split_findings(findings, &files);
+        let content = review_body(summary, &outside_diff);
+        let body = metadata_handler
+            .add_metadata_to_comment(&content, &self.metadata(&content))
+            .context("Can't add Metadata to review")?;
+        Ok((previous_reviews, inline, body))
+    }
+
+    /// Remove the inline comments of a previous review and drop its content and metadata
+    fn supersede_review(&self, pr_number: u64, review: &Review) -> Result<()> {
+        debug!("Superseding review {} on PR#{}", review.id, pr_number);
+        let api = self.github("Posting a review")?;
+        for comment in
+            api.list_review_comments(self.repo_owner(), self.repo_name(), pr_number, review.id)?
+        {
+            api.delete_review_comment(self.repo_owner(), self.repo_name(), comment.id)?;
+        }
+        api.update_review_body(
+            self.repo_owner(),
+            self.repo_name(),
+            pr_number,
+            review.id,
+            "_Superseded by a newer review._",
+        )?;
+        info!(
+            "Successfully superseded review {} on PR#{}",
+            review.id, pr_number
+        );
+        Ok(())
+    }
+
+    /// Delete the generated comments matching the overwrite rules, returning the deleted ones or
+    /// the ones to delete when running dry
+    pub fn delete(&self) -> Result<Vec<PostedComment>> {
+        let pr_number = self.find_pr()?;
+        self.delete_on(pr_number)
+    }
+
+    fn delete_on(&self, pr_number: u64) -> Result<Vec<PostedComment>> {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: METADATA_ID.to_string(),
+        };
+        let comments =
+            self.find_matching_comments(pr_number, &metadata_handler, self.overwrite_mode)?;
+        if comments.is_empty() {
+            info!("No generated comment to delete on PR#{}", pr_number);
+        }
+        let mut deleted = Vec::new();
+        for comment in comments {
+            if self.dry_run {
+                deleted.push((&comment).into());
+                continue;
+            }
+            debug!("Deleting comment {} on PR#{}", comment.id, pr_number);
+            self.forge
+                .delete_comment(self.repo_owner(), self.repo_name(), pr_number, comment.id)
+                .with_context(|| format!("Failed to delete comment {}", comment.id))?;
+            info!(
+                "Successfully deleted comment {} on PR#{}",
+                comment.id, pr_number
+            );
+            deleted.push((&comment).into());
+        }
+        Ok(deleted)
+    }
+
+    /// Fetch the details of the PR
+    pub fn pull_request(&self) -> Result<PullRequest> {
+        let pr_number = self.find_pr()?;
+        self.github("Fetching the PR details")?.get_pull_request(
+            self.repo_owner(),
+            self.repo_name(),
+            pr_number,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::retry::RetryPolicy;
+    use crate::github::transport::{HttpResponse, ScriptedTransport};
+    use crate::github::{Credentials, Pagination, DEFAULT_GITHUB_API_URL};
+    use crate::gitlab::{GitlabAPI, DEFAULT_GITLAB_API_URL};
+    use reqwest::{Method, StatusCode};
+    use serde_json::{json, Value};
+
+    const COMMENTS: &str = "/repos/o/r/issues/1/comments";
+
+    fn scripted_commentator() -> (ScriptedTransport, Commentator) {
+        let transport = ScriptedTransport::new();
+        let api = GithubAPI {
+            base_url: DEFAULT_GITHUB_API_URL.clone(),
+            credentials: Credentials::Token("token".to_owned()),
+            pagination: Pagination::default(),
+            retry: RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            },
+            transport: Box::new(transport.clone()),
+        };
+        (transport, Commentator::new(api).repo("o", "r").pr_number(1))
+    }
+
+    fn comment_json(id: u64, body: &str) -> Value {
+        json!({
+            "id": id,
+            "node_id": format!("IC_{}", id),
+            "html_url": format!("https://github.com/o/r/pull/1#issuecomment-{}", id),
+            "body": body,
+        })
+    }
+
+    fn generated_comment(id: u64, content: &str, identifier: Option<&str>) -> Value {
+        let body = HtmlCommentMetadataHandler {
+            metadata_id: METADATA_ID.to_string(),
+        }
+        .add_metadata_to_comment(
+            &content,
+            &CommentMetadata {
+                identifier: identifier.map(ToOwned::to_owned),
+                ..CommentMetadata::default()
+            }
+            .with_hash(content),
+        )
+        .unwrap();
+        comment_json(id, &body)
+    }
+
+    /// A generated comment created by the run with the given nonce
+    fn created_comment(id: u64, content: &str, nonce: &str) -> Value {
+        let body = HtmlCommentMetadataHandler {
+            metadata_id: METADATA_ID.to_string(),
+        }
+        .add_metadata_to_comment(
+            &content,
+            &CommentMetadata {
+                nonce: Some(nonce.to_owned()),
+                ..CommentMetadata::default()
+            }
+            .with_hash(content),
+        )
+        .unwrap();
+        comment_json(id, &body)
+    }
+
+    /// A comment shared between runs, made of the given sections
+    fn shared_comment(id: u64, sections: &[(&str, &str)]) -> Value {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: SECTIONS_METADATA_ID.to_string(),
+        };
+        let sections = sections
+            .iter()
+            .map(|(name, content)| Section {
+                name: (*name).to_owned(),
+                content: (*content).to_owned(),
+            })
+            .collect::<Vec<_>>();
+        let content = metadata_handler.add_sections_to_comment(&sections).unwrap();
+        let body = metadata_handler
+            .add_metadata_to_comment(&content, &CommentMetadata::default().with_hash(&content))
+            .unwrap();
+        comment_json(id, &body)
+    }
+
+    /// The comment created by the last POST received by the transport
+    fn posted_comment(transport: &ScriptedTransport, id: u64) -> Value {
+        let request = transport
+            .requests()
+            .into_iter()
+            .rev()
+            .find(|r| r.method == Method::POST)
+            .unwrap();
+        let posted: Value = serde_json::from_str(request.body.as_deref().unwrap()).unwrap();
+        comment_json(id, posted["body"].as_str().unwrap())
+    }
+
+    fn posted(id: u64) -> PostedComment {
+        PostedComment {
+            id,
+            html_url: format!("https://github.com/o/r/pull/1#issuecomment-{}", id),
+        }
+    }
+
+    /// Answer with the comment created by the last POST, alone or after the given comments
+    fn respond_with_posted(
+        transport: &ScriptedTransport,
+        method: Method,
+        status: u16,
+        listed: Option<Vec<Value>>,
+        id: u64,
+    ) {
+        let script = transport.clone();
+        transport.respond_with(method, COMMENTS, move |_| {
+            let created = posted_comment(&script, id);
+            let body = match &listed {
+                Some(listed) => {
+                    let mut listed = listed.clone();
+                    listed.push(created);
+                    Value::Array(listed)
+                }
+                None => created,
+            };
+            Ok(HttpResponse::new(
+                StatusCode::from_u16(status).unwrap(),
+                body.to_string(),
+            ))
+        });
+    }
+
+    #[test]
+    fn test_upsert_creates_comment() {
+        let (transport, commentator) = scripted_commentator();
+        transport.respond(Method::GET, COMMENTS, 200, json!([comment_json(1, "LGTM")]));
+        respond_with_posted(&transport, Method::POST, 201, None, 10);
+        respond_with_posted(&transport, Method::GET, 200, Some(vec![]), 10);
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Created(posted(10)));
+        assert!(transport.is_done());
+        let posted_body = transport.requests()[1].body.clone().unwrap();
+        assert!(posted_body.contains("Build succeeded"));
+        assert!(posted_body.contains("nonce"));
+    }
+
+    #[test]
+    fn test_upsert_updates_matching_comment() {
+        let (transport, commentator) = scripted_commentator();
+        transport
+            .respond(
+                Method::GET,
+                COMMENTS,
+                200,
+                json!([
+                    comment_json(1, "LGTM"),
+                    generated_comment(2, "Build failed", None)
+                ]),
+            )
+            .respond(
+                Method::PATCH,
+                "/repos/o/r/issues/comments/2",
+                200,
+                generated_comment(2, "Build succeeded", None),
+            );
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Updated(posted(2)));
+        assert!(transport.is_done());
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1]
+            .body
+            .as_deref()
+            .unwrap()
+            .contains("Build succeeded"));
+    }
+
+    #[test]
+    fn test_upsert_skips_unchanged_comment() {
+        let (transport, commentator) = scripted_commentator();
+        transport.respond(
+            Method::GET,
+            COMMENTS,
+            200,
+            json!([generated_comment(2, "Build succeeded", None)]),
+        );
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Skipped(posted(2)));
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_upsert_skips_unchanged_created_comment() {
+        let (transport, commentator) = scripted_commentator();
+        transport.respond(
+            Method::GET,
+            COMMENTS,
+            200,
+            json!([created_comment(2, "Build succeeded", "previous_run")]),
+        );
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Skipped(posted(2)));
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_upsert_hashes_legacy_comment() {
+        let (transport, commentator) = scripted_commentator();
+        let legacy = comment_json(2, "Build succeeded\n\n<!-- pr_commentator : null -->");
+        transport
+            .respond(Method::GET, COMMENTS, 200, json!([legacy]))
+            .respond(
+                Method::PATCH,
+                "/repos/o/r/issues/comments/2",
+                200,
+                generated_comment(2, "Build succeeded", None),
+            );
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Updated(posted(2)));
+        assert!(transport.requests()[1]
+            .body
+            .as_deref()
+            .unwrap()
+            .contains(&content_hash("Build succeeded")));
+    }
+
+    #[test]
+    fn test_upsert_dry_run() {
+        let (transport, commentator) = scripted_commentator();
+        let commentator = commentator.dry_run(true);
+        transport.respond(
+            Method::GET,
+            COMMENTS,
+            200,
+            json!([generated_comment(2, "Build failed", None)]),
+        );
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        let action = match outcome {
+            CommentOutcome::Planned(plan) => plan.action,
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        };
+        match &action {
+            PlannedAction::Edit { comment, .. } => assert_eq!(comment, &posted(2)),
+            action => panic!("Unexpected action {:?}", action),
+        }
+        let printed = action.to_string();
+        assert!(printed.starts_with("Would edit comment 2 "));
+        assert!(printed.contains("\n-Build failed\n+Build succeeded\n"));
+        assert!(transport
+            .requests()
+            .iter()
+            .all(|request| request.method == Method::GET));
+    }
+
+    #[test]
+    fn test_upsert_using_identifier_ignores_other_identifiers() {
+        let (transport, commentator) = scripted_commentator();
+        let commentator = commentator.overwrite_identifier("lint");
+        let tests_comment = generated_comment(2, "Tests failed", Some("tests"));
+        transport.respond(Method::GET, COMMENTS, 200, json!([tests_comment.clone()]));
+        respond_with_posted(&transport, Method::POST, 201, None, 10);
+        respond_with_posted(&transport, Method::GET, 200, Some(vec![tests_comment]), 10);
+
+        let outcome = commentator
+            .upsert("Lint passed", CommentOverwriteMode::UsingIdentifier)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Created(posted(10)));
+        assert!(transport.is_done());
+        assert_eq!(transport.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_upsert_section_creates_shared_comment() {
+        let (transport, commentator) = scripted_commentator();
+        let commentator = commentator.section_settle_delay(Duration::from_millis(1));
+        transport.respond(Method::GET, COMMENTS, 200, json!([comment_json(1, "LGTM")]));
+        respond_with_posted(&transport, Method::POST, 201, None, 10);
+        respond_with_posted(&transport, Method::GET, 200, Some(vec![]), 10);
+
+        let outcome = commentator
+            .upsert_section(Section {
+                name: "lint".to_owned(),
+                content: "No warnings".to_owned(),
+            })
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Created(posted(10)));
+        assert!(transport.is_done());
+    }
+
+    #[test]
+    fn test_upsert_section_rewritten_after_concurrent_overwrite() {
+        let (transport, commentator) = scripted_commentator();
+        let commentator = commentator.section_settle_delay(Duration::from_millis(1));
+        let tests_passed = shared_comment(5, &[("tests", "Passed")]);
+        let with_lint = shared_comment(5, &[("tests", "Passed"), ("lint", "No warnings")]);
+        // A concurrent run which read the comment before our write overwrites it
+        let tests_failed = shared_comment(5, &[("tests", "Failed")]);
+        let both = shared_comment(5, &[("tests", "Failed"), ("lint", "No warnings")]);
+        transport
+            .respond(Method::GET, COMMENTS, 200, json!([tests_passed]))
+            .respond(
+                Method::PATCH,
+                "/repos/o/r/issues/comments/5",
+                200,
+                with_lint,
+            )
+            .respond(Method::GET, COMMENTS, 200, json!([tests_failed.clone()]))
+            .respond(Method::GET, COMMENTS, 200, json!([tests_failed]))
+            .respond(
+                Method::PATCH,
+                "/repos/o/r/issues/comments/5",
+                200,
+                both.clone(),
+            )
+            .respond(Method::GET, COMMENTS, 200, json!([both]));
+
+        let outcome = commentator
+            .upsert_section(Section {
+                name: "lint".to_owned(),
+                content: "No warnings".to_owned(),
+            })
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Updated(posted(5)));
+        assert!(transport.is_done());
+        let rewritten: Value =
+            serde_json::from_str(transport.requests()[4].body.as_deref().unwrap()).unwrap();
+        let sections = HtmlCommentMetadataHandler {
+            metadata_id: SECTIONS_METADATA_ID.to_string(),
+        }
+        .get_sections_from_comment(rewritten["body"].as_str().unwrap());
+        assert_eq!(
+            sections
+                .iter()
+                .map(|s| (s.name.as_str(), s.content.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("tests", "Failed"), ("lint", "No warnings")]
+        );
+    }
+
+    #[test]
+    fn test_upsert_never_appends() {
+        let (transport, commentator) = scripted_commentator();
+        respond_with_posted(&transport, Method::POST, 201, None, 10);
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Never)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Created(posted(10)));
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_upsert_reconciles_concurrent_creations() {
+        let (transport, commentator) = scripted_commentator();
+        transport.respond(Method::GET, COMMENTS, 200, json!([]));
+        respond_with_posted(&transport, Method::POST, 201, None, 11);
+        respond_with_posted(
+            &transport,
+            Method::GET,
+            200,
+            Some(vec![created_comment(10, "Build failed", "other_run")]),
+            11,
+        );
+        transport
+            .respond(
+                Method::PATCH,
+                "/repos/o/r/issues/comments/10",
+                200,
+                generated_comment(10, "Build succeeded", None),
+            )
+            .respond(
+                Method::DELETE,
+                "/repos/o/r/issues/comments/11",
+                204,
+                Value::Null,
+            );
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Created(posted(10)));
+        assert!(transport.is_done());
+        let edited: Value =
+            serde_json::from_str(transport.requests()[3].body.as_deref().unwrap()).unwrap();
+        let edited = edited["body"].as_str().unwrap();
+        assert!(edited.starts_with("Build succeeded"));
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: METADATA_ID.to_string(),
+        };
+        let metadata: CommentMetadata = metadata_handler
+            .get_metadata_from_comment(edited)
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.nonce.as_deref(), Some("other_run"));
+        assert_eq!(metadata.hash, Some(content_hash("Build succeeded")));
+    }
+
+    #[test]
+    fn test_upsert_reconciles_only_same_identifier() {
+        let (transport, commentator) = scripted_commentator();
+        transport.respond(Method::GET, COMMENTS, 200, json!([]));
+        respond_with_posted(&transport, Method::POST, 201, None, 11);
+        // A concurrent run using another identifier created its comment meanwhile
+        respond_with_posted(
+            &transport,
+            Method::GET,
+            200,
+            Some(vec![generated_comment(10, "Lint passed", Some("lint"))]),
+            11,
+        );
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Created(posted(11)));
+        assert!(transport.is_done());
+        assert_eq!(transport.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_upsert_updates_newest_then_reconciles_duplicates() {
+        let (transport, commentator) = scripted_commentator();
+        // A previous reconciliation failed to delete the duplicate
+        transport
+            .respond(
+                Method::GET,
+                COMMENTS,
+                200,
+                json!([
+                    created_comment(10, "Build failed", "run_a"),
+                    created_comment(11, "Build failed", "run_b")
+                ]),
+            )
+            .respond(
+                Method::PATCH,
+                "/repos/o/r/issues/comments/11",
+                200,
+                created_comment(11, "Build succeeded", "run_b"),
+            )
+            .respond(
+                Method::GET,
+                COMMENTS,
+                200,
+                json!([
+                    created_comment(10, "Build failed", "run_a"),
+                    created_comment(11, "Build succeeded", "run_b")
+                ]),
+            )
+            .respond(
+                Method::PATCH,
+                "/repos/o/r/issues/comments/10",
+                200,
+                created_comment(10, "Build succeeded", "run_a"),
+            )
+            .respond(
+                Method::DELETE,
+                "/repos/o/r/issues/comments/11",
+                204,
+                Value::Null,
+            );
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Updated(posted(10)));
+        assert!(transport.is_done());
+        let edited: Value =
+            serde_json::from_str(transport.requests()[3].body.as_deref().unwrap()).unwrap();
+        assert!(edited["body"]
+            .as_str()
+            .unwrap()
+            .starts_with("Build succeeded"));
+    }
+
+    #[test]
+    fn test_upsert_reconciles_concurrent_split_comments() {
+        let (transport, commentator) = scripted_commentator();
+        let commentator = commentator.oversize_strategy(OversizeStrategy::Split);
+        let comment: String = (0..10_000).map(|i| format!("line {}\n", i)).collect();
+        transport.respond(Method::GET, COMMENTS, 200, json!([]));
+        respond_with_posted(&transport, Method::POST, 201, None, 11);
+        respond_with_posted(&transport, Method::POST, 201, None, 12);
+        // A concurrent run created the same parts first
+        let script = transport.clone();
+        transport.respond_with(Method::GET, COMMENTS, move |_| {
+            let posted: Vec<String> = script
+                .requests()
+                .iter()
+                .filter(|r| r.method == Method::POST)
+                .map(|r| {
+                    let posted: Value = serde_json::from_str(r.body.as_deref().unwrap()).unwrap();
+                    posted["body"].as_str().unwrap().to_owned()
+                })
+                .collect();
+            let listed = json!([
+                comment_json(9, &posted[0]),
+                comment_json(10, &posted[1]),
+                comment_json(11, &posted[0]),
+                comment_json(12, &posted[1]),
+            ]);
+            Ok(HttpResponse::new(StatusCode::OK, listed.to_string()))
+        });
+        for id in &[11, 12] {
+            transport.respond(
+                Method::DELETE,
+                &format!("/repos/o/r/issues/comments/{}", id),
+                204,
+                Value::Null,
+            );
+        }
+
+        let outcome = commentator
+            .upsert(&comment, CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Created(posted(9)));
+        assert!(transport.is_done());
+    }
+
+    #[test]
+    fn test_upsert_doesnt_duplicate_ambiguous_creation() {
+        let (transport, commentator) = scripted_commentator();
+        transport
+            .respond(Method::GET, COMMENTS, 200, json!([]))
+            .fail(Method::POST, COMMENTS, "connection reset");
+        // Listed when checking whether the creation was processed, then when reconciling
+        respond_with_posted(&transport, Method::GET, 200, Some(vec![]), 12);
+        respond_with_posted(&transport, Method::GET, 200, Some(vec![]), 12);
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(outcome, CommentOutcome::Created(posted(12)));
+        assert!(transport.is_done());
+        assert_eq!(
+            transport
+                .requests()
+                .iter()
+                .filter(|r| r.method == Method::POST)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_delete() {
+        let (transport, commentator) = scripted_commentator();
+        transport
+            .respond(
+                Method::GET,
+                COMMENTS,
+                200,
+                json!([
+                    comment_json(1, "LGTM"),
+                    generated_comment(2, "Build failed", None)
+                ]),
+            )
+            .respond(
+                Method::DELETE,
+                "/repos/o/r/issues/comments/2",
+                204,
+                Value::Null,
+            );
+
+        assert_eq!(commentator.delete().unwrap(), vec![posted(2)]);
+        assert!(transport.is_done());
+    }
+
+    #[test]
+    fn test_run_dry() {
+        let (transport, commentator) = scripted_commentator();
+        let commentator = commentator.dry_run(true);
+        transport.respond(
+            Method::GET,
+            COMMENTS,
+            200,
+            json!([generated_comment(2, "Build failed", None)]),
+        );
+
+        let plans = commentator.run(&mut Operation::Delete).unwrap();
+        assert_eq!(
+            plans,
+            vec![Plan::Delete {
+                pr_number: 1,
+                comments: vec![posted(2)]
+            }]
+        );
+        assert_eq!(
+            plans[0].to_string(),
+            "Would delete comment 2 (https://github.com/o/r/pull/1#issuecomment-2)"
+        );
+        assert!(transport.is_done());
+    }
+
+    #[test]
+    fn test_upsert_on_gitlab() {
+        let transport = ScriptedTransport::new();
+        let api = GitlabAPI {
+            base_url: DEFAULT_GITLAB_API_URL.clone(),
+            token: "token".to_owned(),
+            pagination: Pagination::default(),
+            retry: RetryPolicy::default(),
+            transport: Box::new(transport.clone()),
+        };
+        let commentator = Commentator::new(api)
+            .repo("o", "r")
+            .pr_for_ref("refs/merge-requests/3/head");
+        let notes = "/api/v4/projects/o%2Fr/merge_requests/3/notes";
+        let mut system_note = generated_comment(1, "Build failed", None);
+        system_note["system"] = json!(true);
+        transport
+            .respond(
+                Method::GET,
+                notes,
+                200,
+                json!([system_note, generated_comment(2, "Build failed", None)]),
+            )
+            .respond(
+                Method::PUT,
+                &format!("{}/2", notes),
+                200,
+                generated_comment(2, "Build succeeded", None),
+            )
+            .respond(
+                Method::GET,
+                notes,
+                200,
+                json!([generated_comment(2, "Build succeeded", None)]),
+            );
+
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(
+            outcome,
+            CommentOutcome::Updated(PostedComment {
+                id: 2,
+                html_url: "https://gitlab.com/o/r/-/merge_requests/3#note_2".to_owned(),
+            })
+        );
+        let outcome = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert!(matches!(outcome, CommentOutcome::Skipped(_)));
+        assert!(transport.is_done());
+        assert!(commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Minimize)
+            .unwrap_err()
+            .to_string()
+            .contains("only supported on Github"));
+    }
+
+    #[test]
+    fn test_find_pr_for_ref() {
+        let (transport, commentator) = scripted_commentator();
+        let commentator = commentator.pr_for_ref("refs/heads/feature");
+        let user = json!({
+            "login": "octocat", "id": 1, "avatar_url": "", "gravatar_id": "", "url": "",
+            "html_url": "", "followers_url": "", "following_url": "", "gists_url": "",
+            "starred_url": "", "subscriptions_url": "", "organizations_url": "", "repos_url": "",
+            "events_url": "", "received_events_url": "", "site_admin": false
+        });
+        let pr = |number: u64, branch: &str| {
+            json!({
+                "number": number,
+                "state": "open",
+                "head": {
+                    "label": format!("o:{}", branch),
+                    "ref": branch,
+                    "sha": "6dcb09b5b57875f334f61aebed695e2e4193db5e",
+                    "user": user
+                }
+            })
+        };
+        transport.respond(
+            Method::GET,
+            "/repos/o/r/pulls",
+            200,
+            json!([pr(5, "feature-2"), pr(7, "feature")]),
+        );
+
+        assert_eq!(commentator.find_pr().unwrap(), 7);
+        assert_eq!(
+            transport.requests()[0].url.query(),
+            Some("state=open&head=o%3Afeature&per_page=100")
+        );
+    }
+
+    #[test]
+    fn test_commentator_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<GithubAPI>();
+        assert_send::<GitlabAPI>();
+        assert_send::<Commentator>();
+    }
+
+    #[test]
+    fn test_is_matching_comment() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: METADATA_ID.to_string(),
+        };
+        let comment = |body: &str| IssueComment {
+            id: 1,
+            node_id: "MDEyOklzc3VlQ29tbWVudDE=".to_owned(),
+            html_url: "https://github.com/thibaultdelor/GithubPRCommentator/pull/1#issuecomment-1"
+                .to_owned(),
+            body: body.to_owned(),
+        };
+        let with_id = comment("Lint failed\n\n<!-- pr_commentator : \"lint\" -->");
+        let without_id = comment("Lint failed\n\n<!-- pr_commentator : null -->");
+        let human = comment("LGTM");
+        let lint = Some("lint".to_owned());
+
+        for c in &[&with_id, &without_id] {
+            assert!(is_matching_comment(
+                &metadata_handler,
+                CommentOverwriteMode::Always,
+                &None,
+                &c.body
+            ));
+            assert!(!is_matching_comment(
+                &metadata_handler,
+                CommentOverwriteMode::Never,
+                &lint,
+                &c.body
+            ));
+        }
+        assert!(is_matching_comment(
+            &metadata_handler,
+            CommentOverwriteMode::UsingIdentifier,
+            &lint,
+            &with_id.body
+        ));
+        assert!(!is_matching_comment(
+            &metadata_handler,
+            CommentOverwriteMode::UsingIdentifier,
+            &lint,
+            &without_id.body
+        ));
+        assert!(!is_matching_comment(
+            &metadata_handler,
+            CommentOverwriteMode::Always,
+            &None,
+            &human.body
+        ));
+        assert!(is_matching_comment(
+            &metadata_handler,
+            CommentOverwriteMode::Minimize,
+            &None,
+            &without_id.body
+        ));
+        assert!(is_matching_comment(
+            &metadata_handler,
+            CommentOverwriteMode::Minimize,
+            &lint,
+            &with_id.body
+        ));
+        assert!(!is_matching_comment(
+            &metadata_handler,
+            CommentOverwriteMode::Minimize,
+            &lint,
+            &without_id.body
+        ));
+    }
+}
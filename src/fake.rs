@@ -0,0 +1,1353 @@
+//! An in-memory fake of the Github REST endpoints used by pr-commentator, served by the
+//! `fake-github` binary to test pipelines without reaching Github.
+//!
+//! Besides the Github endpoints, it is driven through:
+//! - `POST /_fake/pulls`: add a PR, see `FakePull`
+//! - `POST /_fake/failures`: inject a failure, see `FailureRule`, `DELETE` to remove them all
+//! - `GET /_fake/state`: dump the state, `PUT` to replace it
+//! - `POST /_fake/reset`: drop the state and the failures
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::Utc;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::github::limit::MAX_COMMENT_LENGTH;
+
+/// Login of the author of everything created through the fake
+const FAKE_USER: &str = "pr-commentator[bot]";
+const DEFAULT_PAGE_SIZE: usize = 30;
+const MAX_PAGE_SIZE: usize = 100;
+/// The Checks API refuses more annotations per request
+const MAX_ANNOTATIONS_PER_REQUEST: usize = 50;
+const TOO_LONG_MESSAGE: &str = "body is too long (maximum is 65536 characters)";
+
+fn now() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn open_state() -> String {
+    "open".to_owned()
+}
+
+/// A PR of the fake, the shas must be 40 hexadecimal characters like the real ones
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct FakePull {
+    pub owner: String,
+    pub repo: String,
+    pub number: u64,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default = "open_state")]
+    pub state: String,
+    #[serde(default)]
+    pub merged_at: Option<String>,
+    #[serde(default)]
+    pub author: String,
+    pub head_ref: String,
+    pub head_sha: String,
+    #[serde(default)]
+    pub base_ref: String,
+    #[serde(default)]
+    pub base_sha: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct FakeComment {
+    pub id: u64,
+    pub owner: String,
+    pub repo: String,
+    pub issue_number: u64,
+    pub body: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct FakeReaction {
+    pub id: u64,
+    pub comment_id: u64,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct FakeStatus {
+    pub id: u64,
+    pub owner: String,
+    pub repo: String,
+    pub sha: String,
+    pub state: String,
+    pub context: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub target_url: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FakeCheckRun {
+    pub id: u64,
+    pub owner: String,
+    pub repo: String,
+    pub name: String,
+    pub head_sha: String,
+    pub status: String,
+    #[serde(default)]
+    pub conclusion: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// Accumulated over the updates, as Github does
+    #[serde(default)]
+    pub annotations: Vec<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct FakeGist {
+    pub id: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The content of each file by name
+    pub files: BTreeMap<String, String>,
+}
+
+/// Everything stored by the fake
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FakeState {
+    #[serde(default)]
+    pub pulls: Vec<FakePull>,
+    #[serde(default)]
+    pub comments: Vec<FakeComment>,
+    #[serde(default)]
+    pub reactions: Vec<FakeReaction>,
+    #[serde(default)]
+    pub statuses: Vec<FakeStatus>,
+    #[serde(default)]
+    pub check_runs: Vec<FakeCheckRun>,
+    #[serde(default)]
+    pub gists: Vec<FakeGist>,
+    #[serde(default)]
+    pub last_id: u64,
+}
+
+/// Fail the matching requests instead of processing them, e.g.
+/// `{"path": "/repos/o/r/issues", "method": "POST", "status": 502, "times": 2}`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct FailureRule {
+    /// Only the requests with this method, all if absent
+    #[serde(default)]
+    pub method: Option<String>,
+    /// Only the requests whose path starts with this, all if absent
+    #[serde(default)]
+    pub path: Option<String>,
+    /// The status of the response, 403 by default for rate limits, absent to only slow down
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// Answer as when the rate limit is exhausted, resetting after `retry_after` seconds
+    #[serde(default)]
+    pub rate_limit: bool,
+    /// Seconds to wait told through the `Retry-After` header
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+    /// Wait before responding
+    #[serde(default)]
+    pub delay_ms: Option<u64>,
+    /// Number of requests affected, all if absent
+    #[serde(default)]
+    pub times: Option<u32>,
+}
+
+impl FailureRule {
+    fn matches(&self, request: &FakeRequest, path: &str) -> bool {
+        self.times != Some(0)
+            && self
+                .method
+                .iter()
+                .all(|method| method.eq_ignore_ascii_case(&request.method))
+            && self
+                .path
+                .iter()
+                .all(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// A request received by the fake
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FakeRequest {
+    pub method: String,
+    /// The path and the query
+    pub url: String,
+    /// The value of the `Host` header, used in the urls of the responses
+    pub host: String,
+    pub authorization: Option<String>,
+    pub body: String,
+}
+
+/// The response of the fake, to send after the delay
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FakeResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub delay: Option<Duration>,
+}
+
+impl FakeResponse {
+    fn json(status: u16, body: Value) -> FakeResponse {
+        FakeResponse {
+            status,
+            headers: vec![("Content-Type".to_owned(), "application/json".to_owned())],
+            body: body.to_string(),
+            delay: None,
+        }
+    }
+
+    fn no_content() -> FakeResponse {
+        FakeResponse {
+            status: 204,
+            headers: Vec::new(),
+            body: String::new(),
+            delay: None,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> FakeResponse {
+        FakeResponse::json(status, json!({ "message": message }))
+    }
+
+    fn not_found() -> FakeResponse {
+        FakeResponse::error(404, "Not Found")
+    }
+
+    fn invalid(message: &str) -> FakeResponse {
+        FakeResponse::error(422, &format!("Validation Failed: {}", message))
+    }
+}
+
+fn user_json(login: &str) -> Value {
+    json!({
+        "login": login,
+        "id": 1,
+        "avatar_url": "",
+        "gravatar_id": "",
+        "url": "",
+        "html_url": "",
+        "followers_url": "",
+        "following_url": "",
+        "gists_url": "",
+        "starred_url": "",
+        "subscriptions_url": "",
+        "organizations_url": "",
+        "repos_url": "",
+        "events_url": "",
+        "received_events_url": "",
+        "site_admin": false,
+        "type": if login.ends_with("[bot]") { "Bot" } else { "User" },
+    })
+}
+
+#[derive(Deserialize)]
+struct BodyRequest {
+    body: String,
+}
+
+#[derive(Deserialize)]
+struct GistFileRequest {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct GistRequest {
+    #[serde(default)]
+    description: Option<String>,
+    files: BTreeMap<String, GistFileRequest>,
+}
+
+#[derive(Deserialize)]
+struct ReactionRequest {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct StatusRequest {
+    state: String,
+    #[serde(default)]
+    context: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    target_url: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct CheckRunOutputRequest {
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    summary: Option<String>,
+    #[serde(default)]
+    annotations: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct CheckRunRequest {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    head_sha: Option<String>,
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    conclusion: Option<String>,
+    #[serde(default)]
+    output: Option<CheckRunOutputRequest>,
+}
+
+/// The fake Github, answering the requests from its state
+#[derive(Debug, Clone, Default)]
+pub struct FakeGithub {
+    pub state: FakeState,
+    pub failures: Vec<FailureRule>,
+}
+
+/// Parse the JSON body, or answer 422
+macro_rules! parse_body {
+    ($request:expr) => {
+        match serde_json::from_str(&$request.body) {
+            Ok(body) => body,
+            Err(e) => return FakeResponse::invalid(&e.to_string()),
+        }
+    };
+}
+
+impl FakeGithub {
+    pub fn new(state: FakeState) -> FakeGithub {
+        FakeGithub {
+            state,
+            failures: Vec::new(),
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.state.last_id += 1;
+        self.state.last_id
+    }
+
+    /// Answer the request, failing it if a failure rule matches
+    pub fn handle(&mut self, request: &FakeRequest) -> FakeResponse {
+        let url = match Url::parse(&format!("http://{}{}", request.host, request.url)) {
+            Ok(url) => url,
+            Err(_) => return FakeResponse::error(400, "Invalid url"),
+        };
+        // Github Enterprise serves the api under /api/v3
+        let path = url.path().trim_start_matches("/api/v3").to_owned();
+        if path.starts_with("/_fake/") {
+            return self.handle_admin(request, &path);
+        }
+
+        let mut delay = None;
+        if let Some(rule) = self
+            .failures
+            .iter_mut()
+            .find(|rule| rule.matches(request, &path))
+        {
+            debug!(
+                "Failure rule {:?} matches {} {}",
+                rule, request.method, path
+            );
+            if let Some(times) = rule.times.as_mut() {
+                *times -= 1;
+            }
+            delay = rule.delay_ms.map(Duration::from_millis);
+            if let Some(response) = failure_response(rule) {
+                return FakeResponse { delay, ..response };
+            }
+        }
+        let response = if request.authorization.is_none() {
+            FakeResponse::error(401, "Requires authentication")
+        } else {
+            self.route(request, &url, &path)
+        };
+        FakeResponse { delay, ..response }
+    }
+
+    fn handle_admin(&mut self, request: &FakeRequest, path: &str) -> FakeResponse {
+        match (request.method.as_str(), path) {
+            ("POST", "/_fake/pulls") => {
+                let pull: FakePull = parse_body!(request);
+                self.state.pulls.retain(|p| {
+                    (&p.owner, &p.repo, p.number) != (&pull.owner, &pull.repo, pull.number)
+                });
+                self.state.pulls.push(pull.clone());
+                FakeResponse::json(201, json!(pull))
+            }
+            ("POST", "/_fake/failures") => {
+                let rule: FailureRule = parse_body!(request);
+                self.failures.push(rule.clone());
+                FakeResponse::json(201, json!(rule))
+            }
+            ("DELETE", "/_fake/failures") => {
+                self.failures.clear();
+                FakeResponse::no_content()
+            }
+            ("GET", "/_fake/state") => FakeResponse::json(200, json!(self.state)),
+            ("PUT", "/_fake/state") => {
+                self.state = parse_body!(request);
+                FakeResponse::json(200, json!(self.state))
+            }
+            ("POST", "/_fake/reset") => {
+                *self = FakeGithub::default();
+                FakeResponse::no_content()
+            }
+            _ => FakeResponse::not_found(),
+        }
+    }
+
+    fn route(&mut self, request: &FakeRequest, url: &Url, path: &str) -> FakeResponse {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let host = request.host.as_str();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["repos", owner, repo, "pulls"]) => {
+                let state = query(url, "state").unwrap_or_else(|| "open".to_owned());
+                let head = query(url, "head");
+                let pulls: Vec<Value> = self
+                    .state
+                    .pulls
+                    .iter()
+                    .filter(|p| p.owner == *owner && p.repo == *repo)
+                    .filter(|p| state == "all" || p.state == state)
+                    .filter(|p| {
+                        head.iter()
+                            .all(|head| *head == format!("{}:{}", p.owner, p.head_ref))
+                    })
+                    .map(|p| pull_json(p, host))
+                    .collect();
+                paginate(url, pulls)
+            }
+            ("GET", ["repos", owner, repo, "pulls", number]) => {
+                match self.find_pull(owner, repo, number) {
+                    Some(pull) => FakeResponse::json(200, pull_json(pull, host)),
+                    None => FakeResponse::not_found(),
+                }
+            }
+            ("GET", ["repos", owner, repo, "commits", sha, "pulls"]) => {
+                let pulls: Vec<Value> = self
+                    .state
+                    .pulls
+                    .iter()
+                    .filter(|p| p.owner == *owner && p.repo == *repo && p.head_sha == *sha)
+                    .map(|p| pull_json(p, host))
+                    .collect();
+                paginate(url, pulls)
+            }
+            ("GET", ["repos", owner, repo, "issues", number, "comments"]) => {
+                let number: u64 = number.parse().unwrap_or_default();
+                let comments: Vec<Value> = self
+                    .state
+                    .comments
+                    .iter()
+                    .filter(|c| c.owner == *owner && c.repo == *repo && c.issue_number == number)
+                    .map(|c| comment_json(c, host))
+                    .collect();
+                paginate(url, comments)
+            }
+            ("POST", ["repos", owner, repo, "issues", number, "comments"]) => {
+                let body: BodyRequest = parse_body!(request);
+                if body.body.chars().count() > MAX_COMMENT_LENGTH {
+                    return FakeResponse::invalid(TOO_LONG_MESSAGE);
+                }
+                let issue_number = match number.parse() {
+                    Ok(number) => number,
+                    Err(_) => return FakeResponse::not_found(),
+                };
+                let created_at = now();
+                let comment = FakeComment {
+                    id: self.next_id(),
+                    owner: (*owner).to_owned(),
+                    repo: (*repo).to_owned(),
+                    issue_number,
+                    body: body.body,
+                    updated_at: created_at.clone(),
+                    created_at,
+                };
+                self.state.comments.push(comment.clone());
+                FakeResponse::json(201, comment_json(&comment, host))
+            }
+            (method, ["repos", owner, repo, "issues", "comments", id]) => {
+                let id: u64 = id.parse().unwrap_or_default();
+                let position = match self
+                    .state
+                    .comments
+                    .iter()
+                    .position(|c| c.id == id && c.owner == *owner && c.repo == *repo)
+                {
+                    Some(position) => position,
+                    None => return FakeResponse::not_found(),
+                };
+                match method {
+                    "GET" => {
+                        FakeResponse::json(200, comment_json(&self.state.comments[position], host))
+                    }
+                    "PATCH" => {
+                        let body: BodyRequest = parse_body!(request);
+                        if body.body.chars().count() > MAX_COMMENT_LENGTH {
+                            return FakeResponse::invalid(TOO_LONG_MESSAGE);
+                        }
+                        let comment = &mut self.state.comments[position];
+                        comment.body = body.body;
+                        comment.updated_at = now();
+                        FakeResponse::json(200, comment_json(comment, host))
+                    }
+                    "DELETE" => {
+                        self.state.comments.remove(position);
+                        self.state.reactions.retain(|r| r.comment_id != id);
+                        FakeResponse::no_content()
+                    }
+                    _ => FakeResponse::not_found(),
+                }
+            }
+            (method, ["repos", owner, repo, "issues", "comments", id, "reactions"]) => {
+                let id: u64 = id.parse().unwrap_or_default();
+                if !self
+                    .state
+                    .comments
+                    .iter()
+                    .any(|c| c.id == id && c.owner == *owner && c.repo == *repo)
+                {
+                    return FakeResponse::not_found();
+                }
+                match method {
+                    "GET" => {
+                        let reactions: Vec<Value> = self
+                            .state
+                            .reactions
+                            .iter()
+                            .filter(|r| r.comment_id == id)
+                            .map(reaction_json)
+                            .collect();
+                        paginate(url, reactions)
+                    }
+                    "POST" => {
+                        let reaction: ReactionRequest = parse_body!(request);
+                        // The same user reacting twice the same way gets the existing reaction
+                        if let Some(existing) = self
+                            .state
+                            .reactions
+                            .iter()
+                            .find(|r| r.comment_id == id && r.content == reaction.content)
+                        {
+                            return FakeResponse::json(200, reaction_json(existing));
+                        }
+                        let reaction = FakeReaction {
+                            id: self.next_id(),
+                            comment_id: id,
+                            content: reaction.content,
+                        };
+                        self.state.reactions.push(reaction.clone());
+                        FakeResponse::json(201, reaction_json(&reaction))
+                    }
+                    _ => FakeResponse::not_found(),
+                }
+            }
+            ("POST", ["repos", owner, repo, "statuses", sha]) => {
+                let status: StatusRequest = parse_body!(request);
+                if !["error", "failure", "pending", "success"].contains(&status.state.as_str()) {
+                    return FakeResponse::invalid("state");
+                }
+                let status = FakeStatus {
+                    id: self.next_id(),
+                    owner: (*owner).to_owned(),
+                    repo: (*repo).to_owned(),
+                    sha: (*sha).to_owned(),
+                    state: status.state,
+                    context: status.context.unwrap_or_else(|| "default".to_owned()),
+                    description: status.description,
+                    target_url: status.target_url,
+                    created_at: now(),
+                };
+                self.state.statuses.push(status.clone());
+                FakeResponse::json(201, status_json(&status))
+            }
+            ("GET", ["repos", owner, repo, "commits", sha, "statuses"]) => {
+                let statuses: Vec<Value> = self
+                    .state
+                    .statuses
+                    .iter()
+                    .rev()
+                    .filter(|s| s.owner == *owner && s.repo == *repo && s.sha == *sha)
+                    .map(status_json)
+                    .collect();
+                paginate(url, statuses)
+            }
+            ("POST", ["repos", owner, repo, "check-runs"]) => {
+                let check_run: CheckRunRequest = parse_body!(request);
+                let (name, head_sha) = match (check_run.name.clone(), check_run.head_sha.clone()) {
+                    (Some(name), Some(head_sha)) => (name, head_sha),
+                    _ => return FakeResponse::invalid("name and head_sha are required"),
+                };
+                let mut created = FakeCheckRun {
+                    id: self.next_id(),
+                    owner: (*owner).to_owned(),
+                    repo: (*repo).to_owned(),
+                    name,
+                    head_sha,
+                    status: "queued".to_owned(),
+                    conclusion: None,
+                    title: None,
+                    summary: None,
+                    annotations: Vec::new(),
+                };
+                if let Err(message) = update_check_run(&mut created, check_run) {
+                    return FakeResponse::invalid(message);
+                }
+                self.state.check_runs.push(created.clone());
+                FakeResponse::json(201, check_run_json(&created, host))
+            }
+            (method, ["repos", owner, repo, "check-runs", id]) => {
+                let id: u64 = id.parse().unwrap_or_default();
+                let check_run = match self
+                    .state
+                    .check_runs
+                    .iter_mut()
+                    .find(|c| c.id == id && c.owner == *owner && c.repo == *repo)
+                {
+                    Some(check_run) => check_run,
+                    None => return FakeResponse::not_found(),
+                };
+                match method {
+                    "GET" => FakeResponse::json(200, check_run_json(check_run, host)),
+                    "PATCH" => {
+                        let update: CheckRunRequest = parse_body!(request);
+                        let mut updated = check_run.clone();
+                        if let Err(message) = update_check_run(&mut updated, update) {
+                            return FakeResponse::invalid(message);
+                        }
+                        *check_run = updated;
+                        FakeResponse::json(200, check_run_json(check_run, host))
+                    }
+                    _ => FakeResponse::not_found(),
+                }
+            }
+            ("GET", ["repos", owner, repo, "commits", sha, "check-runs"]) => {
+                let name = query(url, "check_name");
+                let check_runs: Vec<Value> = self
+                    .state
+                    .check_runs
+                    .iter()
+                    .rev()
+                    .filter(|c| c.owner == *owner && c.repo == *repo && c.head_sha == *sha)
+                    .filter(|c| name.iter().all(|name| c.name == *name))
+                    .map(|c| check_run_json(c, host))
+                    .collect();
+                FakeResponse::json(
+                    200,
+                    json!({ "total_count": check_runs.len(), "check_runs": check_runs }),
+                )
+            }
+            ("POST", ["gists"]) => {
+                let gist: GistRequest = parse_body!(request);
+                if gist.files.is_empty() {
+                    return FakeResponse::invalid("files are required");
+                }
+                let gist = FakeGist {
+                    id: format!("{:032x}", self.next_id()),
+                    description: gist.description,
+                    files: gist
+                        .files
+                        .into_iter()
+                        .map(|(name, file)| (name, file.content))
+                        .collect(),
+                };
+                self.state.gists.push(gist.clone());
+                FakeResponse::json(201, gist_json(&gist, host))
+            }
+            (method, ["gists", id]) => {
+                let gist = match self.state.gists.iter_mut().find(|g| g.id == *id) {
+                    Some(gist) => gist,
+                    None => return FakeResponse::not_found(),
+                };
+                match method {
+                    "GET" => FakeResponse::json(200, gist_json(gist, host)),
+                    "PATCH" => {
+                        let update: GistRequest = parse_body!(request);
+                        if update.description.is_some() {
+                            gist.description = update.description;
+                        }
+                        for (name, file) in update.files {
+                            gist.files.insert(name, file.content);
+                        }
+                        FakeResponse::json(200, gist_json(gist, host))
+                    }
+                    _ => FakeResponse::not_found(),
+                }
+            }
+            _ => FakeResponse::not_found(),
+        }
+    }
+
+    fn find_pull(&self, owner: &str, repo: &str, number: &str) -> Option<&FakePull> {
+        let number: u64 = number.parse().ok()?;
+        self.state
+            .pulls
+            .iter()
+            .find(|p| p.owner == owner && p.repo == repo && p.number == number)
+    }
+}
+
+fn failure_response(rule: &FailureRule) -> Option<FakeResponse> {
+    let mut response = if rule.rate_limit {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut response = FakeResponse::error(
+            rule.status.unwrap_or(403),
+            "API rate limit exceeded (injected failure)",
+        );
+        response.headers.extend(vec![
+            ("X-RateLimit-Remaining".to_owned(), "0".to_owned()),
+            (
+                "X-RateLimit-Reset".to_owned(),
+                (now.as_secs() + rule.retry_after.unwrap_or(1)).to_string(),
+            ),
+        ]);
+        response
+    } else {
+        FakeResponse::error(rule.status?, "Injected failure")
+    };
+    if let Some(retry_after) = rule.retry_after {
+        response
+            .headers
+            .push(("Retry-After".to_owned(), retry_after.to_string()));
+    }
+    Some(response)
+}
+
+fn update_check_run(
+    check_run: &mut FakeCheckRun,
+    update: CheckRunRequest,
+) -> Result<(), &'static str> {
+    if let Some(name) = update.name {
+        check_run.name = name;
+    }
+    if let Some(status) = update.status {
+        if !["queued", "in_progress", "completed"].contains(&status.as_str()) {
+            return Err("status");
+        }
+        check_run.status = status;
+    }
+    if update.conclusion.is_some() {
+        check_run.status = "completed".to_owned();
+        check_run.conclusion = update.conclusion;
+    }
+    if check_run.status == "completed" && check_run.conclusion.is_none() {
+        return Err("conclusion is required when completed");
+    }
+    let output = update.output.unwrap_or_default();
+    if output.annotations.len() > MAX_ANNOTATIONS_PER_REQUEST {
+        return Err("too many annotations");
+    }
+    check_run.title = output.title.or_else(|| check_run.title.take());
+    check_run.summary = output.summary.or_else(|| check_run.summary.take());
+    check_run.annotations.extend(output.annotations);
+    Ok(())
+}
+
+fn query(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Answer a page of the items, linking to the next one
+fn paginate(url: &Url, items: Vec<Value>) -> FakeResponse {
+    let per_page = query(url, "per_page")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let page = query(url, "page")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1usize)
+        .max(1);
+    let page_items: Vec<Value> = items
+        .iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .cloned()
+        .collect();
+    let mut response = FakeResponse::json(200, Value::Array(page_items));
+    if page * per_page < items.len() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(key, _)| key != "page")
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect();
+        let mut next = url.clone();
+        next.query_pairs_mut()
+            .clear()
+            .extend_pairs(pairs)
+            .append_pair("page", &(page + 1).to_string());
+        response
+            .headers
+            .push(("Link".to_owned(), format!("<{}>; rel=\"next\"", next)));
+    }
+    response
+}
+
+fn pull_json(pull: &FakePull, host: &str) -> Value {
+    let repo = json!({
+        "full_name": format!("{}/{}", pull.owner, pull.repo),
+        "html_url": format!("http://{}/{}/{}", host, pull.owner, pull.repo),
+    });
+    let branch = |branch: &str, sha: &str| {
+        json!({
+            "label": format!("{}:{}", pull.owner, branch),
+            "ref": branch,
+            "sha": sha,
+            "user": user_json(&pull.owner),
+            "repo": repo,
+        })
+    };
+    json!({
+        "number": pull.number,
+        "title": pull.title,
+        "state": pull.state,
+        "merged_at": pull.merged_at,
+        "html_url": format!("http://{}/{}/{}/pull/{}", host, pull.owner, pull.repo, pull.number),
+        "user": user_json(if pull.author.is_empty() { &pull.owner } else { &pull.author }),
+        "head": branch(&pull.head_ref, &pull.head_sha),
+        "base": branch(&pull.base_ref, &pull.base_sha),
+    })
+}
+
+fn comment_json(comment: &FakeComment, host: &str) -> Value {
+    json!({
+        "id": comment.id,
+        "node_id": format!("IC_{}", comment.id),
+        "html_url": format!(
+            "http://{}/{}/{}/pull/{}#issuecomment-{}",
+            host, comment.owner, comment.repo, comment.issue_number, comment.id
+        ),
+        "body": comment.body,
+        "user": user_json(FAKE_USER),
+        "created_at": comment.created_at,
+        "updated_at": comment.updated_at,
+    })
+}
+
+fn gist_json(gist: &FakeGist, host: &str) -> Value {
+    let files: BTreeMap<&String, Value> = gist
+        .files
+        .iter()
+        .map(|(name, content)| (name, json!({ "filename": name, "content": content })))
+        .collect();
+    json!({
+        "id": gist.id,
+        "html_url": format!("http://{}/gist/{}", host, gist.id),
+        "description": gist.description,
+        "public": false,
+        "files": files,
+        "owner": user_json(FAKE_USER),
+    })
+}
+
+fn reaction_json(reaction: &FakeReaction) -> Value {
+    json!({
+        "id": reaction.id,
+        "content": reaction.content,
+        "user": user_json(FAKE_USER),
+    })
+}
+
+fn status_json(status: &FakeStatus) -> Value {
+    json!({
+        "id": status.id,
+        "state": status.state,
+        "context": status.context,
+        "description": status.description,
+        "target_url": status.target_url,
+        "created_at": status.created_at,
+        "creator": user_json(FAKE_USER),
+    })
+}
+
+fn check_run_json(check_run: &FakeCheckRun, host: &str) -> Value {
+    json!({
+        "id": check_run.id,
+        "name": check_run.name,
+        "head_sha": check_run.head_sha,
+        "status": check_run.status,
+        "conclusion": check_run.conclusion,
+        "html_url": format!(
+            "http://{}/{}/{}/runs/{}",
+            host, check_run.owner, check_run.repo, check_run.id
+        ),
+        "output": {
+            "title": check_run.title,
+            "summary": check_run.summary,
+            "annotations_count": check_run.annotations.len(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commentator::{CommentOutcome, CommentOverwriteMode, Commentator, OversizeStrategy};
+    use crate::github::checks::{
+        Annotation, AnnotationLevel, CheckRunConclusion, CheckRunReport, CheckRunStatus,
+    };
+    use crate::github::retry::RetryPolicy;
+    use crate::github::transport::{HttpRequest, HttpResponse, Transport};
+    use crate::github::{Credentials, GithubAPI, Pagination};
+    use anyhow::Result;
+    use reqwest::header::{HeaderName, HeaderValue};
+    use reqwest::StatusCode;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    const SHA: &str = "6dcb09b5b57875f334f61aebed695e2e4193db5e";
+
+    fn fake() -> FakeGithub {
+        FakeGithub::new(FakeState {
+            pulls: vec![FakePull {
+                owner: "o".to_owned(),
+                repo: "r".to_owned(),
+                number: 1,
+                title: "Add the fake".to_owned(),
+                state: "open".to_owned(),
+                merged_at: None,
+                author: "octocat".to_owned(),
+                head_ref: "feature".to_owned(),
+                head_sha: SHA.to_owned(),
+                base_ref: "master".to_owned(),
+                base_sha: SHA.to_owned(),
+            }],
+            ..FakeState::default()
+        })
+    }
+
+    fn request(method: &str, url: &str, body: Value) -> FakeRequest {
+        FakeRequest {
+            method: method.to_owned(),
+            url: url.to_owned(),
+            host: "localhost:8080".to_owned(),
+            authorization: Some("token abc".to_owned()),
+            body: if body.is_null() {
+                String::new()
+            } else {
+                body.to_string()
+            },
+        }
+    }
+
+    fn body(response: &FakeResponse) -> Value {
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    fn header<'a>(response: &'a FakeResponse, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn test_pulls() {
+        let mut github = fake();
+        let open = github.handle(&request(
+            "GET",
+            "/repos/o/r/pulls?head=o%3Afeature",
+            Value::Null,
+        ));
+        assert_eq!(open.status, 200);
+        assert_eq!(body(&open)[0]["number"], 1);
+        let other = github.handle(&request(
+            "GET",
+            "/repos/o/r/pulls?head=o%3Aother",
+            Value::Null,
+        ));
+        assert_eq!(body(&other), json!([]));
+        let closed = github.handle(&request(
+            "GET",
+            "/repos/o/r/pulls?state=closed",
+            Value::Null,
+        ));
+        assert_eq!(body(&closed), json!([]));
+
+        let pull = github.handle(&request("GET", "/api/v3/repos/o/r/pulls/1", Value::Null));
+        assert_eq!(body(&pull)["head"]["ref"], "feature");
+        assert_eq!(body(&pull)["user"]["login"], "octocat");
+        let by_sha = github.handle(&request(
+            "GET",
+            &format!("/repos/o/r/commits/{}/pulls", SHA),
+            Value::Null,
+        ));
+        assert_eq!(body(&by_sha)[0]["number"], 1);
+        assert_eq!(
+            github
+                .handle(&request("GET", "/repos/o/r/pulls/2", Value::Null))
+                .status,
+            404
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut github = fake();
+        for i in 1..=3 {
+            let created = github.handle(&request(
+                "POST",
+                "/repos/o/r/issues/1/comments",
+                json!({ "body": format!("comment {}", i) }),
+            ));
+            assert_eq!(created.status, 201);
+        }
+
+        let first_page = github.handle(&request(
+            "GET",
+            "/repos/o/r/issues/1/comments?per_page=2",
+            Value::Null,
+        ));
+        assert_eq!(body(&first_page).as_array().unwrap().len(), 2);
+        assert_eq!(
+            header(&first_page, "link"),
+            Some("<http://localhost:8080/repos/o/r/issues/1/comments?per_page=2&page=2>; rel=\"next\"")
+        );
+        let second_page = github.handle(&request(
+            "GET",
+            "/repos/o/r/issues/1/comments?per_page=2&page=2",
+            Value::Null,
+        ));
+        assert_eq!(body(&second_page)[0]["body"], "comment 3");
+        assert_eq!(header(&second_page, "link"), None);
+
+        let edited = github.handle(&request(
+            "PATCH",
+            "/repos/o/r/issues/comments/1",
+            json!({ "body": "edited" }),
+        ));
+        assert_eq!(body(&edited)["body"], "edited");
+        let reaction = github.handle(&request(
+            "POST",
+            "/repos/o/r/issues/comments/1/reactions",
+            json!({ "content": "+1" }),
+        ));
+        assert_eq!(reaction.status, 201);
+        let same_reaction = github.handle(&request(
+            "POST",
+            "/repos/o/r/issues/comments/1/reactions",
+            json!({ "content": "+1" }),
+        ));
+        assert_eq!(same_reaction.status, 200);
+
+        let deleted = github.handle(&request(
+            "DELETE",
+            "/repos/o/r/issues/comments/1",
+            Value::Null,
+        ));
+        assert_eq!(deleted.status, 204);
+        assert_eq!(
+            github
+                .handle(&request("GET", "/repos/o/r/issues/comments/1", Value::Null))
+                .status,
+            404
+        );
+        assert!(github.state.reactions.is_empty());
+
+        let unauthenticated = FakeRequest {
+            authorization: None,
+            ..request("GET", "/repos/o/r/issues/1/comments", Value::Null)
+        };
+        assert_eq!(github.handle(&unauthenticated).status, 401);
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut github = fake();
+        let failure = github.handle(&request(
+            "POST",
+            "/_fake/failures",
+            json!({ "method": "POST", "path": "/repos/o/r/issues", "status": 502, "times": 2 }),
+        ));
+        assert_eq!(failure.status, 201);
+        let post = request(
+            "POST",
+            "/repos/o/r/issues/1/comments",
+            json!({ "body": "hello" }),
+        );
+        assert_eq!(github.handle(&post).status, 502);
+        assert_eq!(
+            github
+                .handle(&request("GET", "/repos/o/r/issues/1/comments", Value::Null))
+                .status,
+            200
+        );
+        assert_eq!(github.handle(&post).status, 502);
+        assert_eq!(github.handle(&post).status, 201);
+
+        github.failures.push(FailureRule {
+            rate_limit: true,
+            retry_after: Some(5),
+            times: Some(1),
+            ..FailureRule::default()
+        });
+        let rate_limited = github.handle(&post);
+        assert_eq!(rate_limited.status, 403);
+        assert_eq!(header(&rate_limited, "x-ratelimit-remaining"), Some("0"));
+        assert_eq!(header(&rate_limited, "retry-after"), Some("5"));
+
+        github.failures = vec![FailureRule {
+            delay_ms: Some(1500),
+            ..FailureRule::default()
+        }];
+        let slow = github.handle(&post);
+        assert_eq!(slow.status, 201);
+        assert_eq!(slow.delay, Some(Duration::from_millis(1500)));
+
+        github.handle(&request("DELETE", "/_fake/failures", Value::Null));
+        assert!(github.failures.is_empty());
+    }
+
+    #[test]
+    fn test_check_runs() {
+        let mut github = fake();
+        let incomplete = github.handle(&request(
+            "POST",
+            "/repos/o/r/check-runs",
+            json!({ "name": "lint", "head_sha": SHA, "status": "completed" }),
+        ));
+        assert_eq!(incomplete.status, 422);
+
+        let created = github.handle(&request(
+            "POST",
+            "/repos/o/r/check-runs",
+            json!({
+                "name": "lint",
+                "head_sha": SHA,
+                "status": "in_progress",
+                "output": { "title": "Lint", "summary": "Running", "annotations": [{}] }
+            }),
+        ));
+        assert_eq!(created.status, 201);
+        let id = body(&created)["id"].as_u64().unwrap();
+        let updated = github.handle(&request(
+            "PATCH",
+            &format!("/repos/o/r/check-runs/{}", id),
+            json!({ "conclusion": "failure", "output": { "annotations": [{}, {}] } }),
+        ));
+        assert_eq!(body(&updated)["status"], "completed");
+        assert_eq!(body(&updated)["output"]["annotations_count"], 3);
+        assert_eq!(body(&updated)["output"]["title"], "Lint");
+
+        let listed = github.handle(&request(
+            "GET",
+            &format!("/repos/o/r/commits/{}/check-runs?check_name=lint", SHA),
+            Value::Null,
+        ));
+        assert_eq!(body(&listed)["total_count"], 1);
+        let status = github.handle(&request(
+            "POST",
+            &format!("/repos/o/r/statuses/{}", SHA),
+            json!({ "state": "success", "context": "lint" }),
+        ));
+        assert_eq!(status.status, 201);
+    }
+
+    /// Send the requests of the real client to the fake, without network
+    struct FakeTransport(Arc<Mutex<FakeGithub>>);
+
+    impl Transport for FakeTransport {
+        fn send(&self, request: &HttpRequest) -> Result<HttpResponse> {
+            let url = &request.url;
+            let response = self.0.lock().unwrap().handle(&FakeRequest {
+                method: request.method.to_string(),
+                url: match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_owned(),
+                },
+                host: url.host_str().unwrap_or_default().to_owned(),
+                authorization: request.header("Authorization").map(ToOwned::to_owned),
+                body: request.body.clone().unwrap_or_default(),
+            });
+            let mut http_response =
+                HttpResponse::new(StatusCode::from_u16(response.status)?, response.body);
+            for (name, value) in response.headers {
+                http_response
+                    .headers
+                    .insert(HeaderName::from_str(&name)?, HeaderValue::from_str(&value)?);
+            }
+            Ok(http_response)
+        }
+    }
+
+    fn fake_commentator(github: &Arc<Mutex<FakeGithub>>) -> Commentator {
+        Commentator::new(GithubAPI {
+            base_url: Url::parse("http://localhost:8080/").unwrap(),
+            credentials: Credentials::Token("token".to_owned()),
+            pagination: Pagination::default(),
+            retry: RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            },
+            transport: Box::new(FakeTransport(github.clone())),
+        })
+        .repo("o", "r")
+        .pr_for_ref("refs/heads/feature")
+    }
+
+    #[test]
+    fn test_commentator_against_fake() {
+        let github = Arc::new(Mutex::new(fake()));
+        github.lock().unwrap().failures.push(FailureRule {
+            method: Some("GET".to_owned()),
+            status: Some(502),
+            times: Some(1),
+            ..FailureRule::default()
+        });
+        let commentator = fake_commentator(&github);
+
+        let created = commentator
+            .upsert("Build failed", CommentOverwriteMode::Always)
+            .unwrap();
+        assert!(matches!(created, CommentOutcome::Created(_)));
+        assert_eq!(
+            created.comment().unwrap().html_url,
+            format!(
+                "http://localhost/o/r/pull/1#issuecomment-{}",
+                created.comment().unwrap().id
+            )
+        );
+        let skipped = commentator
+            .upsert("Build failed", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(
+            skipped,
+            CommentOutcome::Skipped(created.comment().unwrap().clone())
+        );
+        let updated = commentator
+            .upsert("Build succeeded", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(
+            updated,
+            CommentOutcome::Updated(created.comment().unwrap().clone())
+        );
+
+        let comments = &github.lock().unwrap().state.comments;
+        assert_eq!(comments.len(), 1);
+        assert!(comments[0].body.starts_with("Build succeeded"));
+    }
+
+    #[test]
+    fn test_oversized_comments_against_fake() {
+        let github = Arc::new(Mutex::new(fake()));
+        let long_comment = |lines: usize| {
+            let logs: String = (0..lines).map(|i| format!("line {}\n", i)).collect();
+            format!(
+                "<details><summary>Logs</summary>\n\n```\n{}```\n</details>\n",
+                logs
+            )
+        };
+        let bodies = |github: &Arc<Mutex<FakeGithub>>| -> Vec<String> {
+            github
+                .lock()
+                .unwrap()
+                .state
+                .comments
+                .iter()
+                .map(|c| c.body.clone())
+                .collect()
+        };
+
+        let truncated = fake_commentator(&github)
+            .upsert(&long_comment(20_000), CommentOverwriteMode::Always)
+            .unwrap();
+        assert!(matches!(truncated, CommentOutcome::Created(_)));
+        let body = &bodies(&github)[0];
+        assert!(body.chars().count() <= MAX_COMMENT_LENGTH);
+        assert!(body.contains("```\n</details>\n\n---\n_The comment was truncated"));
+
+        let split = fake_commentator(&github).oversize_strategy(OversizeStrategy::Split);
+        split
+            .upsert(&long_comment(15_000), CommentOverwriteMode::Always)
+            .unwrap();
+        let parts = bodies(&github);
+        assert_eq!(parts.len(), 3);
+        assert!(parts
+            .iter()
+            .all(|part| part.chars().count() <= MAX_COMMENT_LENGTH));
+        assert!(parts[2].contains("_Part 3/3_"));
+        assert!(parts[1].starts_with("<details>\n```\n"));
+        split
+            .upsert(&long_comment(10_000), CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(bodies(&github).len(), 2);
+        split
+            .upsert("All good", CommentOverwriteMode::Always)
+            .unwrap();
+        assert_eq!(bodies(&github).len(), 1);
+        assert!(bodies(&github)[0].starts_with("All good"));
+
+        let gist = fake_commentator(&github).oversize_strategy(OversizeStrategy::Gist);
+        let created = gist
+            .upsert(&long_comment(20_000), CommentOverwriteMode::Always)
+            .unwrap();
+        assert!(matches!(created, CommentOutcome::Updated(_)));
+        let gists = github.lock().unwrap().state.gists.clone();
+        assert_eq!(gists.len(), 1);
+        assert!(bodies(&github)[0].contains(&format!("(http://localhost/gist/{})", gists[0].id)));
+        assert!(gists[0].files["comment.md"].ends_with("line 19999\n```\n</details>\n"));
+        let skipped = gist
+            .upsert(&long_comment(20_000), CommentOverwriteMode::Always)
+            .unwrap();
+        assert!(matches!(skipped, CommentOutcome::Skipped(_)));
+        gist.upsert(&long_comment(15_000), CommentOverwriteMode::Always)
+            .unwrap();
+        let gists = &github.lock().unwrap().state.gists;
+        assert_eq!(gists.len(), 1);
+        assert!(gists[0].files["comment.md"].ends_with("line 14999\n```\n</details>\n"));
+    }
+
+    #[test]
+    fn test_check_run_updated_against_fake() {
+        let github = Arc::new(Mutex::new(fake()));
+        let commentator = fake_commentator(&github);
+        let mut report = CheckRunReport {
+            name: "lint".to_owned(),
+            head_sha: SHA.to_owned(),
+            status: CheckRunStatus::InProgress,
+            conclusion: None,
+            title: "Lint".to_owned(),
+            summary: "Running".to_owned(),
+            annotations: Vec::new(),
+        };
+        let in_progress = commentator.publish_check_run(&report).unwrap().unwrap();
+
+        report.status = CheckRunStatus::Completed;
+        report.conclusion = Some(CheckRunConclusion::Failure);
+        report.summary = "120 warnings".to_owned();
+        report.annotations = (1..=120)
+            .map(|line| Annotation {
+                path: "src/main.rs".to_owned(),
+                start_line: line,
+                end_line: line,
+                annotation_level: AnnotationLevel::Warning,
+                message: "unused variable".to_owned(),
+            })
+            .collect();
+        let completed = commentator.publish_check_run(&report).unwrap().unwrap();
+        let republished = commentator.publish_check_run(&report).unwrap().unwrap();
+        assert_eq!(completed.id, in_progress.id);
+        assert_eq!(republished.id, in_progress.id);
+        let check_runs = &github.lock().unwrap().state.check_runs;
+        assert_eq!(check_runs.len(), 1);
+        assert_eq!(check_runs[0].status, "completed");
+        assert_eq!(check_runs[0].annotations.len(), 120);
+    }
+}
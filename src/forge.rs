@@ -0,0 +1,84 @@
+use std::fmt;
+
+use anyhow::Result;
+use strum_macros::{Display, EnumString, EnumVariantNames};
+
+use crate::github::{GithubAPI, IssueComment};
+
+/// The code hosting service holding the repo
+#[derive(
+    Debug,
+    EnumString,
+    EnumVariantNames,
+    Display,
+    PartialEq,
+    Eq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Clone,
+    Copy,
+    Default,
+)]
+pub enum ForgeKind {
+    #[default]
+    Github,
+    GitLab,
+}
+
+/// The operations on the comments of a change request, i.e. a Github PR or a GitLab MR whose
+/// comments are called notes. Everything else is only available on Github through `github`.
+pub trait Forge: fmt::Debug + Send + Sync {
+    fn kind(&self) -> ForgeKind;
+
+    /// The number of the open change request of a git reference, either a branch or a change
+    /// request reference (e.g. `refs/pull/1/head` or `refs/merge-requests/1/head`)
+    fn find_pr_for_ref(&self, repo_owner: &str, repo_name: &str, git_ref: &str) -> Result<u64>;
+
+    /// The comments of the change request written by users, oldest first
+    fn list_comments(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+    ) -> Result<Vec<IssueComment>>;
+
+    /// Create the comment at most once: after an ambiguous failure the comments are listed
+    /// before any retry, and the one recognized by `is_created` is returned if found
+    fn comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        body: &str,
+        is_created: &dyn Fn(&IssueComment) -> bool,
+    ) -> Result<IssueComment>;
+
+    fn edit_comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        comment_id: u64,
+        body: &str,
+    ) -> Result<IssueComment>;
+
+    fn delete_comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        comment_id: u64,
+    ) -> Result<()>;
+
+    /// The maximum number of characters of a comment body
+    fn max_comment_length(&self) -> usize;
+
+    /// Replace every secret currently used by this client in the text by its masked version
+    fn mask_secrets(&self, text: &str) -> String;
+
+    /// The Github api, for the features only Github has (e.g. check runs, reviews or gists)
+    fn github(&self) -> Option<&GithubAPI> {
+        None
+    }
+}
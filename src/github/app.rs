@@ -0,0 +1,144 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+
+/// Installation tokens are refreshed when they expire in less than this
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// Github refuses JWT valid for more than 10 minutes
+const JWT_VALIDITY: Duration = Duration::from_secs(9 * 60);
+/// Leeway for clock drift between us and Github
+const JWT_CLOCK_DRIFT: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+struct JwtClaims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Installation {
+    pub id: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct InstallationToken {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl InstallationToken {
+    fn is_fresh(&self, now: SystemTime) -> bool {
+        SystemTime::from(self.expires_at) > now + TOKEN_REFRESH_MARGIN
+    }
+}
+
+/// A Github App, authenticating as the installation on the repo it comments on
+pub struct GithubApp {
+    pub app_id: u64,
+    pub repo_owner: String,
+    pub repo_name: String,
+    private_key: EncodingKey,
+    installation_token: Mutex<Option<InstallationToken>>,
+}
+
+impl GithubApp {
+    pub fn new(
+        app_id: u64,
+        private_key_pem: &[u8],
+        repo_owner: &str,
+        repo_name: &str,
+    ) -> Result<GithubApp> {
+        Ok(GithubApp {
+            app_id,
+            repo_owner: repo_owner.to_owned(),
+            repo_name: repo_name.to_owned(),
+            private_key: EncodingKey::from_rsa_pem(private_key_pem)
+                .context("Invalid Github App private key, expecting a RSA key in PEM format")?,
+            installation_token: Mutex::new(None),
+        })
+    }
+
+    /// Sign a JWT authenticating as the app itself
+    pub fn jwt(&self) -> Result<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("System clock is before 1970")?;
+        let claims = JwtClaims {
+            iat: (now - JWT_CLOCK_DRIFT).as_secs(),
+            exp: (now + JWT_VALIDITY).as_secs(),
+            iss: self.app_id.to_string(),
+        };
+        encode(&Header::new(Algorithm::RS256), &claims, &self.private_key)
+            .context("Failed to sign Github App JWT")
+    }
+
+    /// Return the cached installation token, or fetch a new one if missing or about to expire
+    pub fn installation_token<F>(&self, fetch: F) -> Result<String>
+    where
+        F: FnOnce(&str) -> Result<InstallationToken>,
+    {
+        let mut cached = self
+            .installation_token
+            .lock()
+            .map_err(|_| anyhow!("Installation token lock poisoned"))?;
+        match cached.as_ref() {
+            Some(token) if token.is_fresh(SystemTime::now()) => Ok(token.token.clone()),
+            _ => {
+                let token = fetch(&self.jwt()?)?;
+                let value = token.token.clone();
+                *cached = Some(token);
+                Ok(value)
+            }
+        }
+    }
+
+    /// The installation token currently in use, if any
+    pub fn current_token(&self) -> Option<String> {
+        self.installation_token
+            .lock()
+            .ok()
+            .and_then(|cached| cached.as_ref().map(|t| t.token.clone()))
+    }
+}
+
+impl fmt::Debug for GithubApp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GithubApp {{ app_id: {}, installation repo: '{}/{}' }}",
+            self.app_id, self.repo_owner, self.repo_name
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn token(expires_at: &str) -> InstallationToken {
+        InstallationToken {
+            token: "ghs_token".to_owned(),
+            expires_at: DateTime::from_str(expires_at).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_installation_token_freshness() {
+        let now = SystemTime::from(DateTime::<Utc>::from_str("2020-01-01T12:00:00Z").unwrap());
+        assert!(token("2020-01-01T13:00:00Z").is_fresh(now));
+        assert!(!token("2020-01-01T12:04:00Z").is_fresh(now));
+        assert!(!token("2020-01-01T11:00:00Z").is_fresh(now));
+    }
+
+    #[test]
+    fn test_invalid_private_key() {
+        assert!(GithubApp::new(1, b"not a pem", "thibaultdelor", "GithubPRCommentator").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString, EnumVariantNames};
+use url::form_urlencoded;
+
+use super::review::Finding;
+use super::GithubAPI;
+
+/// The Checks API refuses more annotations per request
+pub const MAX_ANNOTATIONS_PER_REQUEST: usize = 50;
+
+#[derive(
+    Serialize, Debug, EnumString, EnumVariantNames, Display, PartialEq, Eq, Clone, Copy, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckRunStatus {
+    Queued,
+    InProgress,
+    Completed,
+}
+
+#[derive(
+    Serialize, Debug, EnumString, EnumVariantNames, Display, PartialEq, Eq, Clone, Copy, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckRunConclusion {
+    Success,
+    Failure,
+    Neutral,
+    Cancelled,
+    Skipped,
+    TimedOut,
+    ActionRequired,
+}
+
+#[derive(
+    Serialize, Debug, EnumString, EnumVariantNames, Display, PartialEq, Eq, Clone, Copy, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnotationLevel {
+    Notice,
+    Warning,
+    Failure,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Annotation {
+    pub path: String,
+    pub start_line: u64,
+    pub end_line: u64,
+    pub annotation_level: AnnotationLevel,
+    pub message: String,
+}
+
+impl Annotation {
+    pub fn from_finding(finding: &Finding, annotation_level: AnnotationLevel) -> Annotation {
+        Annotation {
+            path: finding.path.clone(),
+            start_line: finding.line,
+            end_line: finding.line,
+            annotation_level,
+            message: finding.message.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+struct CheckRunOutput<'a> {
+    title: &'a str,
+    summary: &'a str,
+    annotations: &'a [Annotation],
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+struct CheckRunRequest<'a> {
+    name: &'a str,
+    head_sha: &'a str,
+    status: CheckRunStatus,
+    // Only allowed, and then required, once completed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conclusion: Option<CheckRunConclusion>,
+    output: CheckRunOutput<'a>,
+}
+
+/// What is published as a check run
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CheckRunReport {
+    pub name: String,
+    pub head_sha: String,
+    pub status: CheckRunStatus,
+    pub conclusion: Option<CheckRunConclusion>,
+    pub title: String,
+    pub summary: String,
+    pub annotations: Vec<Annotation>,
+}
+
+impl CheckRunReport {
+    /// The annotations to send, none when updating a check run which already has some as the
+    /// Checks API appends them to the existing ones
+    pub fn new_annotations(&self, existing_check_run: Option<&CheckRun>) -> &[Annotation] {
+        match existing_check_run {
+            Some(check_run) if check_run.output.annotations_count > 0 => &[],
+            _ => &self.annotations,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct CheckRunOutputSummary {
+    #[serde(default)]
+    pub annotations_count: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct CheckRun {
+    pub id: u64,
+    pub name: String,
+    pub html_url: String,
+    #[serde(default)]
+    pub output: CheckRunOutputSummary,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+struct CheckRunList {
+    check_runs: Vec<CheckRun>,
+}
+
+/// Split the annotations in batches accepted by the Checks API, at least one possibly empty batch
+fn annotation_batches(annotations: &[Annotation]) -> Vec<&[Annotation]> {
+    if annotations.is_empty() {
+        vec![annotations]
+    } else {
+        annotations.chunks(MAX_ANNOTATIONS_PER_REQUEST).collect()
+    }
+}
+
+impl GithubAPI {
+    /// The check runs of the commit with the given name, most recent first
+    pub fn find_check_runs(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        sha: &str,
+        name: &str,
+    ) -> Result<Vec<CheckRun>> {
+        let name_filter: String = form_urlencoded::byte_serialize(name.as_bytes()).collect();
+        self.request(
+            Method::GET,
+            &format!(
+                "repos/{}/{}/commits/{}/check-runs?check_name={}&filter=latest",
+                repo_owner, repo_name, sha, name_filter
+            ),
+        )?
+        .send()
+        .context("Listing check runs failed")
+        .and_then(|res| {
+            if res.status() == 200 {
+                res.json::<CheckRunList>()
+                    .map(|list| list.check_runs)
+                    .context("Failed to deserialize check runs")
+            } else {
+                Err(anyhow!(
+                    "Github returned unexpected status : {}",
+                    res.status()
+                ))
+            }
+        })
+    }
+
+    fn send_check_run(
+        &self,
+        method: Method,
+        url: &str,
+        report: &CheckRunReport,
+        annotations: &[Annotation],
+    ) -> Result<CheckRun> {
+        let expected_status = if method == Method::POST { 201 } else { 200 };
+        self.request(method, url)?
+            .json(&CheckRunRequest {
+                name: &report.name,
+                head_sha: &report.head_sha,
+                status: report.status,
+                conclusion: report.conclusion,
+                output: CheckRunOutput {
+                    title: &report.title,
+                    summary: &report.summary,
+                    annotations,
+                },
+            })
+            .send()
+            .context("Publishing check run failed")
+            .and_then(|res| {
+                if res.status() == expected_status {
+                    res.json().context("Failed to deserialize check run")
+                } else {
+                    Err(anyhow!(
+                        "Github returned unexpected status : {}",
+                        res.status()
+                    ))
+                }
+            })
+    }
+
+    /// Create the check run, or update the given one, then add the remaining annotations
+    /// by batches of 50. See `CheckRunReport::new_annotations` for the annotations sent.
+    pub fn publish_check_run(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        existing_check_run: Option<&CheckRun>,
+        report: &CheckRunReport,
+    ) -> Result<CheckRun> {
+        let mut batches =
+            annotation_batches(report.new_annotations(existing_check_run)).into_iter();
+        let first_batch = batches.next().unwrap_or_default();
+        let check_run = match existing_check_run {
+            Some(existing_check_run) => {
+                debug!("Updating check run {}", existing_check_run.id);
+                self.send_check_run(
+                    Method::PATCH,
+                    &format!(
+                        "repos/{}/{}/check-runs/{}",
+                        repo_owner, repo_name, existing_check_run.id
+                    ),
+                    report,
+                    first_batch,
+                )?
+            }
+            None => {
+                debug!("Creating check run {} on {}", report.name, report.head_sha);
+                self.send_check_run(
+                    Method::POST,
+                    &format!("repos/{}/{}/check-runs", repo_owner, repo_name),
+                    report,
+                    first_batch,
+                )?
+            }
+        };
+        for batch in batches {
+            debug!(
+                "Adding {} annotations to check run {}",
+                batch.len(),
+                check_run.id
+            );
+            self.send_check_run(
+                Method::PATCH,
+                &format!(
+                    "repos/{}/{}/check-runs/{}",
+                    repo_owner, repo_name, check_run.id
+                ),
+                report,
+                batch,
+            )?;
+        }
+        Ok(check_run)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotation(line: u64) -> Annotation {
+        Annotation {
+            path: "src/main.rs".to_owned(),
+            start_line: line,
+            end_line: line,
+            annotation_level: AnnotationLevel::Warning,
+            message: "unused variable".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_annotation_batches() {
+        assert_eq!(annotation_batches(&[]), vec![&[] as &[Annotation]]);
+        let annotations: Vec<Annotation> = (1..=120).map(annotation).collect();
+        let batches = annotation_batches(&annotations);
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect::<Vec<_>>(),
+            vec![50, 50, 20]
+        );
+        assert_eq!(batches[2][0], annotation(101));
+    }
+
+    #[test]
+    fn test_new_annotations() {
+        let report = CheckRunReport {
+            name: "lint".to_owned(),
+            head_sha: "abc".to_owned(),
+            status: CheckRunStatus::Completed,
+            conclusion: Some(CheckRunConclusion::Failure),
+            title: "Lint".to_owned(),
+            summary: "1 warning".to_owned(),
+            annotations: vec![annotation(3)],
+        };
+        let check_run = |annotations_count| CheckRun {
+            id: 1,
+            name: "lint".to_owned(),
+            html_url: "https://github.com/o/r/runs/1".to_owned(),
+            output: CheckRunOutputSummary { annotations_count },
+        };
+        assert_eq!(report.new_annotations(None), &[annotation(3)]);
+        assert_eq!(
+            report.new_annotations(Some(&check_run(0))),
+            &[annotation(3)]
+        );
+        assert!(report.new_annotations(Some(&check_run(1))).is_empty());
+    }
+
+    #[test]
+    fn test_check_run_request() {
+        let request = CheckRunRequest {
+            name: "lint",
+            head_sha: "abc",
+            status: CheckRunStatus::InProgress,
+            conclusion: None,
+            output: CheckRunOutput {
+                title: "Lint",
+                summary: "Running",
+                annotations: &[annotation(3)],
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            serde_json::json!({
+                "name": "lint",
+                "head_sha": "abc",
+                "status": "in_progress",
+                "output": {
+                    "title": "Lint",
+                    "summary": "Running",
+                    "annotations": [{
+                        "path": "src/main.rs",
+                        "start_line": 3,
+                        "end_line": 3,
+                        "annotation_level": "warning",
+                        "message": "unused variable"
+                    }]
+                }
+            })
+        );
+    }
+}
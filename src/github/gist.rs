@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use super::GithubAPI;
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct GistFile {
+    pub content: String,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct GistWriteRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Only used when creating, the gist is secret if false
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public: Option<bool>,
+    pub files: BTreeMap<String, GistFile>,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Gist {
+    pub id: String,
+    pub html_url: String,
+}
+
+impl GistWriteRequest {
+    fn single_file(file_name: &str, content: &str) -> BTreeMap<String, GistFile> {
+        let mut files = BTreeMap::new();
+        files.insert(
+            file_name.to_owned(),
+            GistFile {
+                content: content.to_owned(),
+            },
+        );
+        files
+    }
+}
+
+impl GithubAPI {
+    /// Create a secret gist with a single file. Gists can't be created with an app token.
+    pub fn create_gist(&self, description: &str, file_name: &str, content: &str) -> Result<Gist> {
+        debug!("Creating gist {}", description);
+        self.request(Method::POST, "gists")?
+            .json(&GistWriteRequest {
+                description: Some(description.to_owned()),
+                public: Some(false),
+                files: GistWriteRequest::single_file(file_name, content),
+            })
+            .send()
+            .context("Creating gist failed")
+            .and_then(|res| {
+                if res.status() == 201 {
+                    res.json().context("Failed to deserialize gist")
+                } else {
+                    Err(anyhow!(
+                        "Github returned unexpected status : {}",
+                        res.status()
+                    ))
+                }
+            })
+    }
+
+    /// Replace the content of the file of the gist
+    pub fn update_gist(&self, gist_id: &str, file_name: &str, content: &str) -> Result<Gist> {
+        debug!("Updating gist {}", gist_id);
+        self.request(Method::PATCH, &format!("gists/{}", gist_id))?
+            .json(&GistWriteRequest {
+                description: None,
+                public: None,
+                files: GistWriteRequest::single_file(file_name, content),
+            })
+            .send()
+            .context("Updating gist failed")
+            .and_then(|res| {
+                if res.status() == 200 {
+                    res.json().context("Failed to deserialize gist")
+                } else {
+                    Err(anyhow!(
+                        "Github returned unexpected status : {}",
+                        res.status()
+                    ))
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gist_write_request() {
+        let request = GistWriteRequest {
+            description: Some("Logs".to_owned()),
+            public: Some(false),
+            files: GistWriteRequest::single_file("comment.md", "Hi"),
+        };
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            serde_json::json!({
+                "description": "Logs",
+                "public": false,
+                "files": {"comment.md": {"content": "Hi"}}
+            })
+        );
+        let update = GistWriteRequest {
+            description: None,
+            public: None,
+            ..request
+        };
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            serde_json::json!({"files": {"comment.md": {"content": "Hi"}}})
+        );
+    }
+}
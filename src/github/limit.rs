@@ -0,0 +1,183 @@
+/// The maximum number of characters of a comment body, Github rejects longer ones with a 422
+pub const MAX_COMMENT_LENGTH: usize = 65536;
+
+/// The markdown blocks open at some point of a text, to close them before a cut and reopen them
+/// after it
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+struct OpenBlocks {
+    /// The line opening the code fence, e.g. "```rust"
+    fence: Option<String>,
+    /// The number of `<details>` tags not closed
+    details: usize,
+}
+
+impl OpenBlocks {
+    /// Update the blocks with a line of the text
+    fn push_line(&mut self, line: &str) {
+        let trimmed = line.trim();
+        match &self.fence {
+            Some(fence) => {
+                let marker = fence_marker(fence);
+                if trimmed.starts_with(marker) && trimmed[marker.len()..].trim().is_empty() {
+                    self.fence = None;
+                }
+            }
+            None if !fence_marker(trimmed).is_empty() => self.fence = Some(trimmed.to_owned()),
+            None => {
+                let lowercase = trimmed.to_lowercase();
+                self.details += lowercase.matches("<details").count();
+                self.details = self
+                    .details
+                    .saturating_sub(lowercase.matches("</details>").count());
+            }
+        }
+    }
+
+    fn closing(&self) -> String {
+        let mut closing = String::new();
+        if let Some(fence) = &self.fence {
+            closing.push('\n');
+            closing.push_str(fence_marker(fence));
+        }
+        for _ in 0..self.details {
+            closing.push_str("\n</details>");
+        }
+        closing
+    }
+
+    fn reopening(&self) -> String {
+        let mut reopening = "<details>\n".repeat(self.details);
+        if let Some(fence) = &self.fence {
+            reopening.push_str(fence);
+            reopening.push('\n');
+        }
+        reopening
+    }
+}
+
+/// The run of backticks or tildes opening a code fence, empty if the line doesn't open one
+fn fence_marker(line: &str) -> &str {
+    let marker_char = match line.chars().next() {
+        Some(c) if c == '`' || c == '~' => c,
+        _ => return "",
+    };
+    let length = line.chars().take_while(|c| *c == marker_char).count();
+    if length >= 3 {
+        &line[..length]
+    } else {
+        ""
+    }
+}
+
+/// Cut the text in a head of at most `max_length` characters and the rest, preferably at the end
+/// of a line. The code fences and the `<details>` tags open at the cut are closed at the end of
+/// the head and reopened at the start of the rest. Return the text as is if short enough.
+pub fn split_markdown(text: &str, max_length: usize) -> (String, Option<String>) {
+    if text.chars().count() <= max_length {
+        return (text.to_owned(), None);
+    }
+    let mut blocks = OpenBlocks::default();
+    // The last cut at the end of a line which fits, with its length and the blocks open there
+    let mut line_cut: Option<(usize, usize, OpenBlocks)> = None;
+    let mut length = 0;
+    let mut position = 0;
+    for line in text.split_inclusive('\n') {
+        let line_length = line.chars().count();
+        if length + line_length > max_length {
+            break;
+        }
+        length += line_length;
+        position += line.len();
+        blocks.push_line(line);
+        if length + blocks.closing().chars().count() <= max_length {
+            line_cut = Some((position, length, blocks.clone()));
+        }
+    }
+    // Cut in the middle of the line not fitting rather than keeping too little, e.g. a long log
+    // line in a code block would otherwise never fit
+    let (position, blocks) = match line_cut {
+        Some((position, length, blocks)) if length * 2 >= max_length => (position, blocks),
+        _ => {
+            let available = max_length.saturating_sub(length + blocks.closing().chars().count());
+            match (available, line_cut) {
+                (0, Some((position, _, blocks))) => (position, blocks),
+                _ => {
+                    let in_line = text[position..]
+                        .char_indices()
+                        .nth(available)
+                        .map(|(in_line, _)| in_line)
+                        .unwrap_or_else(|| text.len() - position);
+                    (position + in_line, blocks)
+                }
+            }
+        }
+    };
+    let head = format!("{}{}", text[..position].trim_end(), blocks.closing());
+    let rest = format!("{}{}", blocks.reopening(), &text[position..]);
+    (head, Some(rest))
+}
+
+/// Keep the start of the text fitting in `max_length` characters with the notice, see
+/// `split_markdown`
+pub fn truncate_markdown(text: &str, max_length: usize, notice: &str) -> String {
+    match split_markdown(text, max_length.saturating_sub(notice.chars().count())) {
+        (head, Some(_)) => format!("{}{}", head, notice),
+        (text, None) => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_markdown_short_text() {
+        assert_eq!(split_markdown("Hi", 2), ("Hi".to_owned(), None));
+    }
+
+    #[test]
+    fn test_split_markdown_at_line_end() {
+        let (head, rest) = split_markdown("first line\nsecond line\n", 15);
+        assert_eq!(head, "first line");
+        assert_eq!(rest.as_deref(), Some("second line\n"));
+    }
+
+    #[test]
+    fn test_split_markdown_respects_utf8() {
+        let (head, rest) = split_markdown("éééé", 3);
+        assert_eq!(head, "ééé");
+        assert_eq!(rest.as_deref(), Some("é"));
+    }
+
+    #[test]
+    fn test_split_markdown_closes_blocks() {
+        let text = "<details><summary>Logs</summary>\n\n```text\nline 1\nline 2\nline 3\n```\n</details>\n";
+        let (head, rest) = split_markdown(text, 64);
+        assert_eq!(
+            head,
+            "<details><summary>Logs</summary>\n\n```text\nline 1\n```\n</details>"
+        );
+        assert_eq!(
+            rest.as_deref(),
+            Some("<details>\n```text\nline 2\nline 3\n```\n</details>\n")
+        );
+        assert!(head.chars().count() <= 64);
+    }
+
+    #[test]
+    fn test_split_markdown_long_line_in_block() {
+        let text = format!("```\n{}\n```\n", "x".repeat(100));
+        let (head, rest) = split_markdown(&text, 30);
+        assert_eq!(head, format!("```\n{}\n```", "x".repeat(22)));
+        assert_eq!(rest.unwrap(), format!("```\n{}\n```\n", "x".repeat(78)));
+    }
+
+    #[test]
+    fn test_truncate_markdown() {
+        let text = "a\n".repeat(100);
+        let truncated = truncate_markdown(&text, 50, "\n_truncated_");
+        assert!(truncated.chars().count() <= 50);
+        assert!(truncated.ends_with("a\n_truncated_"));
+        assert_eq!(truncate_markdown("a\n", 50, "\n_truncated_"), "a\n");
+    }
+}
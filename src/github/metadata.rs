@@ -1,5 +1,166 @@
 use anyhow::{Context, Result};
+use ring::digest;
 use serde;
+use serde::{Deserialize, Serialize, Serializer};
+
+/// The metadata of the generated comments
+#[derive(Deserialize, Debug, Clone, Default, Eq, PartialEq, Hash)]
+#[serde(from = "MetadataFormat")]
+pub struct CommentMetadata {
+    /// The identifier used to select the comments to overwrite
+    pub identifier: Option<String>,
+    /// Unique to each created comment, to find it back after an ambiguous failure
+    pub nonce: Option<String>,
+    /// The hash of the content, to skip the edits that wouldn't change it
+    pub hash: Option<String>,
+    /// The number of the part, from 2, of a comment split in several ones
+    pub part: Option<u32>,
+    /// The gist holding the end of the comment
+    pub gist: Option<GistReference>,
+}
+
+/// Written in the legacy format when it only holds the identifier, so that the previous
+/// versions still recognize the comment
+impl Serialize for CommentMetadata {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.nonce.is_none() && self.hash.is_none() && self.part.is_none() && self.gist.is_none()
+        {
+            return self.identifier.serialize(serializer);
+        }
+        CurrentFormat {
+            identifier: self.identifier.as_ref(),
+            nonce: self.nonce.as_ref(),
+            hash: self.hash.as_ref(),
+            part: self.part,
+            gist: self.gist.as_ref(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A gist linked from a comment
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct GistReference {
+    pub id: String,
+    pub html_url: String,
+    /// The hash of the gist content, to skip the updates that wouldn't change it
+    pub hash: String,
+}
+
+impl CommentMetadata {
+    /// The same metadata with a new random nonce
+    pub fn with_nonce(self) -> CommentMetadata {
+        CommentMetadata {
+            nonce: Some(format!(
+                "{:016x}{:016x}",
+                rand::random::<u64>(),
+                rand::random::<u64>()
+            )),
+            ..self
+        }
+    }
+
+    /// The part number, the first part having none
+    pub fn part_number(&self) -> u32 {
+        self.part.unwrap_or(1)
+    }
+
+    /// The same metadata for a comment with this content
+    pub fn with_hash(self, content: &str) -> CommentMetadata {
+        CommentMetadata {
+            hash: Some(content_hash(content)),
+            ..self
+        }
+    }
+}
+
+/// The hex encoded SHA-256 of the content
+pub fn content_hash(content: &str) -> String {
+    digest::digest(&digest::SHA256, content.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[derive(Serialize)]
+struct CurrentFormat<'a> {
+    identifier: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    part: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gist: Option<&'a GistReference>,
+}
+
+/// The comments generated by the previous versions only hold the identifier
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MetadataFormat {
+    Current {
+        #[serde(default)]
+        identifier: Option<String>,
+        #[serde(default)]
+        nonce: Option<String>,
+        #[serde(default)]
+        hash: Option<String>,
+        #[serde(default)]
+        part: Option<u32>,
+        #[serde(default)]
+        gist: Option<GistReference>,
+    },
+    Legacy(Option<String>),
+}
+
+impl From<MetadataFormat> for CommentMetadata {
+    fn from(format: MetadataFormat) -> CommentMetadata {
+        match format {
+            MetadataFormat::Current {
+                identifier,
+                nonce,
+                hash,
+                part,
+                gist,
+            } => CommentMetadata {
+                identifier,
+                nonce,
+                hash,
+                part,
+                gist,
+            },
+            MetadataFormat::Legacy(identifier) => CommentMetadata {
+                identifier,
+                ..CommentMetadata::default()
+            },
+        }
+    }
+}
+
+/// A named part of a comment shared by several runs, each run owning its own section
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Section {
+    pub name: String,
+    pub content: String,
+}
+
+/// Replace the section with the same name, or append it if absent
+pub fn upsert_section(sections: &mut Vec<Section>, section: Section) {
+    match sections.iter_mut().find(|s| s.name == section.name) {
+        Some(existing) => existing.content = section.content,
+        None => sections.push(section),
+    }
+}
+
+/// Metadata of the HTML comments delimiting a section
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SectionBoundary {
+    Start(String),
+    End(String),
+}
 
 /// Append a HTML comment to the content of the message containing the metadata as json
 pub struct HtmlCommentMetadataHandler {
@@ -38,10 +199,11 @@ impl HtmlCommentMetadataHandler {
         comment: &str,
     ) -> Option<Result<M>> {
         let prefix = &self.prefix();
-        let position: Option<(usize, usize)> = comment.find(prefix).and_then(|start| {
+        // The metadata is appended at the end, after any section boundary
+        let position: Option<(usize, usize)> = comment.rfind(prefix).and_then(|start| {
             let meta_start = start + prefix.len();
-            let end = comment.find(&self.suffix());
-            end.map(|e| (meta_start, e))
+            let end = comment[meta_start..].find(&self.suffix());
+            end.map(|e| (meta_start, meta_start + e))
         });
         if let Some((start, end)) = position {
             Some(serde_json::from_str(&comment[start..end]).context("Failed to parse metadata"))
@@ -49,11 +211,86 @@ impl HtmlCommentMetadataHandler {
             None
         }
     }
+
+    /// The content of the comment, without the metadata
+    pub fn get_content_from_comment<'a>(&self, comment: &'a str) -> &'a str {
+        match comment.rfind(&self.prefix()) {
+            Some(start) => &comment[..start],
+            None => comment,
+        }
+    }
+
+    fn section_boundary(&self, boundary: &SectionBoundary) -> Result<String> {
+        serde_json::to_string(boundary)
+            .context("Failed to serialize section boundary")
+            .map(|json| format!("<!-- {}{}{}", self.metadata_id, json, self.suffix()))
+    }
+
+    /// Join the sections, each one delimited by HTML comments holding its name
+    pub fn add_sections_to_comment(&self, sections: &[Section]) -> Result<String> {
+        sections
+            .iter()
+            .map(|section| {
+                Ok(format!(
+                    "{}\n{}\n{}",
+                    self.section_boundary(&SectionBoundary::Start(section.name.clone()))?,
+                    section.content.trim(),
+                    self.section_boundary(&SectionBoundary::End(section.name.clone()))?
+                ))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|sections| sections.join("\n\n"))
+    }
+
+    /// The sections of the comment in order, boundaries not matching a section are ignored
+    pub fn get_sections_from_comment(&self, comment: &str) -> Vec<Section> {
+        let marker_start = format!("<!-- {}", self.metadata_id);
+        let suffix = self.suffix();
+        let mut sections = Vec::new();
+        let mut current: Option<(String, usize)> = None;
+        let mut position = 0;
+        while let Some(start) = comment[position..]
+            .find(&marker_start)
+            .map(|s| position + s)
+        {
+            let json_start = start + marker_start.len();
+            let end = match comment[json_start..].find(&suffix) {
+                Some(end) => json_start + end,
+                None => break,
+            };
+            position = end + suffix.len();
+            match serde_json::from_str(&comment[json_start..end]) {
+                Ok(SectionBoundary::Start(name)) => current = Some((name, position)),
+                Ok(SectionBoundary::End(name)) => {
+                    if let Some((current_name, content_start)) = current.take() {
+                        if current_name == name {
+                            sections.push(Section {
+                                name,
+                                content: comment[content_start..start].trim().to_owned(),
+                            });
+                        }
+                    }
+                }
+                // Not a section boundary, e.g. the comment metadata
+                Err(_) => {}
+            }
+        }
+        sections
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::HtmlCommentMetadataHandler;
+    use super::{
+        content_hash, upsert_section, CommentMetadata, HtmlCommentMetadataHandler, Section,
+    };
+
+    fn section(name: &str, content: &str) -> Section {
+        Section {
+            name: name.to_owned(),
+            content: content.to_owned(),
+        }
+    }
 
     #[test]
     fn test_add_get_metadata() {
@@ -80,5 +317,140 @@ mod tests {
         assert!(metadata_handler
             .get_metadata_from_comment::<()>(comment)
             .is_none());
+        assert_eq!(
+            comment,
+            metadata_handler.get_content_from_comment(expected_full_com)
+        );
+        assert_eq!(comment, metadata_handler.get_content_from_comment(comment));
+    }
+
+    #[test]
+    fn test_metadata_after_html_comment() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        let comment = "<!-- a user comment -->\n\n<!-- aaaa[1] -->\n\n<!-- aaaa[1,2] -->";
+        assert_eq!(
+            vec![1, 2],
+            metadata_handler
+                .get_metadata_from_comment::<Vec<u64>>(comment)
+                .unwrap()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_add_get_sections() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        let sections = vec![
+            section("lint", "No issues\n"),
+            section("tests", "42 passed"),
+        ];
+        let body = metadata_handler
+            .add_sections_to_comment(&sections)
+            .and_then(|body| metadata_handler.add_metadata_to_comment(&body, &"id"))
+            .unwrap();
+        assert_eq!(
+            body,
+            "<!-- aaaa{\"start\":\"lint\"} -->\nNo issues\n<!-- aaaa{\"end\":\"lint\"} -->\n\n\
+             <!-- aaaa{\"start\":\"tests\"} -->\n42 passed\n<!-- aaaa{\"end\":\"tests\"} -->\
+             \n\n<!-- aaaa\"id\" -->"
+        );
+        assert_eq!(
+            metadata_handler.get_sections_from_comment(&body),
+            vec![section("lint", "No issues"), section("tests", "42 passed")]
+        );
+        assert_eq!(
+            metadata_handler
+                .get_metadata_from_comment::<String>(&body)
+                .unwrap()
+                .unwrap(),
+            "id"
+        );
+    }
+
+    #[test]
+    fn test_upsert_section() {
+        let mut sections = vec![section("lint", "old"), section("tests", "42 passed")];
+        upsert_section(&mut sections, section("lint", "new"));
+        upsert_section(&mut sections, section("bench", "fast"));
+        assert_eq!(
+            sections,
+            vec![
+                section("lint", "new"),
+                section("tests", "42 passed"),
+                section("bench", "fast")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comment_metadata_formats() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        let metadata = |comment: &str| {
+            metadata_handler
+                .get_metadata_from_comment::<CommentMetadata>(comment)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(
+            metadata("Hi\n\n<!-- aaaanull -->"),
+            CommentMetadata::default()
+        );
+        assert_eq!(
+            metadata("Hi\n\n<!-- aaaa\"lint\" -->")
+                .identifier
+                .as_deref(),
+            Some("lint")
+        );
+        let identified = CommentMetadata {
+            identifier: Some("lint".to_owned()),
+            ..CommentMetadata::default()
+        };
+        assert_eq!(
+            metadata_handler
+                .add_metadata_to_comment(&"Hi", &identified)
+                .unwrap(),
+            "Hi\n\n<!-- aaaa\"lint\" -->"
+        );
+        assert_eq!(
+            metadata_handler
+                .add_metadata_to_comment(&"Hi", &CommentMetadata::default())
+                .unwrap(),
+            "Hi\n\n<!-- aaaanull -->"
+        );
+
+        let created = CommentMetadata {
+            identifier: Some("lint".to_owned()),
+            ..CommentMetadata::default()
+        }
+        .with_nonce();
+        let comment = metadata_handler
+            .add_metadata_to_comment(&"Hi", &created)
+            .unwrap();
+        assert_eq!(metadata(&comment), created);
+        assert_ne!(created.nonce, CommentMetadata::default().with_nonce().nonce);
+
+        let hashed = CommentMetadata {
+            part: Some(2),
+            ..created.with_hash("Hi")
+        };
+        let comment = metadata_handler
+            .add_metadata_to_comment(&"Hi", &hashed)
+            .unwrap();
+        assert_eq!(metadata(&comment), hashed);
+    }
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(
+            content_hash("Hi"),
+            "3639efcd08abb273b1619e82e78c29a7df02c1051b1820e99fc395dcaa3326b8"
+        );
+        assert_ne!(content_hash("Hi"), content_hash("Hi "));
     }
 }
@@ -1,21 +1,43 @@
+pub mod app;
+pub mod checks;
+pub mod gist;
+pub mod limit;
 pub mod metadata;
+pub mod retry;
+pub mod review;
+pub mod status;
+pub mod transport;
 
+use crate::forge::{Forge, ForgeKind};
+use crate::gitlab::DEFAULT_GITLAB_API_URL;
 use anyhow::{anyhow, Context, Result};
+use app::{GithubApp, Installation, InstallationToken};
 use github_types::ShortCommit;
 use lazy_static::lazy_static;
-use log::debug;
+use limit::MAX_COMMENT_LENGTH;
+use log::{debug, warn};
 use regex::Regex;
-use reqwest::{Method, RequestBuilder};
+use reqwest::header::LINK;
+use reqwest::Method;
+use retry::{GithubRequest, RetryPolicy};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::fmt;
 use std::iter::FromIterator;
 use std::str::FromStr;
-use url::Url;
+use strum_macros::{Display, EnumString, EnumVariantNames};
+use transport::Transport;
+use url::{form_urlencoded, Url};
+
+const BRANCH_REF_PREFIX: &str = "refs/heads/";
 
 lazy_static! {
     pub static ref DEFAULT_GITHUB_API_URL: Url = Url::from_str("https://api.github.com/").unwrap();
     pub static ref PR_BRANCH_GITHUB_PATTERN: Regex =
         Regex::new(r"^refs/pull/(\d+)/(?:head|merge)$").unwrap();
+    static ref NEXT_PAGE_LINK_PATTERN: Regex =
+        Regex::new(r#"^\s*<([^>]+)>\s*;.*\brel\s*=\s*"?next"?"#).unwrap();
 }
 
 #[derive(Serialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -26,19 +48,176 @@ pub struct CommentCreateRequest {
 #[derive(Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct IssueComment {
     pub id: u64,
+    /// The id of the comment in the GraphQL api
+    #[serde(default)]
+    pub node_id: String,
+    #[serde(default)]
+    pub html_url: String,
     pub body: String,
 }
 
+#[derive(Serialize, Debug, Clone)]
+struct GraphqlRequest<'a, V: Serialize> {
+    query: &'a str,
+    variables: V,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct GraphqlResponse<D> {
+    data: Option<D>,
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct GraphqlError {
+    pub message: String,
+}
+
+const MINIMIZE_COMMENT_MUTATION: &str = "
+mutation($id: ID!) {
+  minimizeComment(input: {subjectId: $id, classifier: OUTDATED}) {
+    minimizedComment { isMinimized }
+  }
+}";
+
+const MINIMIZED_STATE_QUERY: &str = "
+query($ids: [ID!]!) {
+  nodes(ids: $ids) {
+    ... on IssueComment { id isMinimized }
+  }
+}";
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct MinimizedState {
+    id: String,
+    is_minimized: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+struct MinimizedStateNodes {
+    // Deleted comments are returned as null nodes
+    nodes: Vec<Option<MinimizedState>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PullRequestUser {
+    pub login: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PullRequestRepo {
+    pub full_name: String,
+    pub html_url: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PullRequestBranch {
+    #[serde(rename = "ref")]
+    pub branch: String,
+    pub sha: String,
+    /// Absent when the fork the PR comes from was deleted
+    #[serde(default)]
+    pub repo: Option<PullRequestRepo>,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PullRequest {
+    pub number: u64,
+    pub title: String,
+    pub html_url: String,
+    pub user: PullRequestUser,
+    pub head: PullRequestBranch,
+    pub base: PullRequestBranch,
+}
+
 // The api to retrieve the list of PR doesn't return all the fields of the PR
 #[derive(Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct PullRequestSummary {
     pub number: u64,
     pub head: ShortCommit,
+    #[serde(default)]
+    pub state: String,
+    #[serde(default)]
+    pub merged_at: Option<String>,
+}
+
+impl PullRequestSummary {
+    pub fn is_open(&self) -> bool {
+        self.state == "open"
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged_at.is_some()
+    }
+}
+
+/// Define which PR to pick when several PRs are associated with a commit
+#[derive(Debug, EnumString, EnumVariantNames, Display, PartialEq, Eq, Clone, Copy, Default)]
+pub enum PrStatePreference {
+    /// Pick the most recent open PR
+    #[default]
+    Open,
+    /// Pick the most recent merged PR
+    Merged,
+}
+
+/// Pick the PR matching the preference, the most recent one (highest number) wins
+fn select_pr_by_state(
+    prs: &[PullRequestSummary],
+    preference: PrStatePreference,
+) -> Option<&PullRequestSummary> {
+    prs.iter()
+        .filter(|pr| match preference {
+            PrStatePreference::Open => pr.is_open(),
+            PrStatePreference::Merged => pr.is_merged(),
+        })
+        .max_by_key(|pr| pr.number)
+}
+
+/// Define how list endpoints are walked through
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Pagination {
+    /// Number of items requested per page (Github caps it at 100)
+    pub page_size: u32,
+    /// Maximum number of pages fetched for a single list call
+    pub max_pages: u32,
+}
+
+impl Default for Pagination {
+    fn default() -> Pagination {
+        Pagination {
+            page_size: 100,
+            max_pages: 50,
+        }
+    }
+}
+
+/// How requests to Github are authenticated
+pub enum Credentials {
+    /// A personal access token (or any other OAuth token)
+    Token(String),
+    /// A Github App, exchanging a JWT for an installation token
+    App(GithubApp),
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Token(token) => write!(f, "Token('{}')", mask_token(&mut token.clone())),
+            Credentials::App(app) => write!(f, "App({:?})", app),
+        }
+    }
 }
 
 pub struct GithubAPI {
     pub base_url: Url,
-    pub token: String,
+    pub credentials: Credentials,
+    pub pagination: Pagination,
+    pub retry: RetryPolicy,
+    /// Send the requests, e.g. `transport::ReqwestTransport`
+    pub transport: Box<dyn Transport>,
 }
 
 fn mask_token(token: &mut String) -> &mut String {
@@ -56,25 +235,177 @@ fn mask_token(token: &mut String) -> &mut String {
     token
 }
 
+/// Replace every occurrence of the token in the text by its masked version
+pub fn mask_token_in(text: &str, token: &str) -> String {
+    if token.is_empty() {
+        text.to_owned()
+    } else {
+        text.replace(token, mask_token(&mut token.to_owned()))
+    }
+}
+
 impl fmt::Debug for GithubAPI {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "GithubAPI {{ base_url: '{}',  token: '{}' }}",
-            self.base_url,
-            mask_token(&mut self.token.clone())
+            "GithubAPI {{ base_url: '{}',  credentials: {:?}, pagination: {:?}, retry: {:?} }}",
+            self.base_url, self.credentials, self.pagination, self.retry
         )
     }
 }
 
+/// The GraphQL endpoint, `/api/graphql` on Github Enterprise, `/graphql` on github.com
+fn graphql_url(base_url: &Url) -> Result<Url> {
+    let endpoint = if base_url.path().trim_end_matches('/').ends_with("/api/v3") {
+        "../graphql"
+    } else {
+        "graphql"
+    };
+    base_url
+        .join(endpoint)
+        .with_context(|| format!("Couldnt determine graphql url for {}", base_url))
+}
+
+/// Strip the `refs/heads/` prefix of a git reference to get the bare branch name
+pub(crate) fn branch_name_from_ref(git_ref: &str) -> &str {
+    git_ref.strip_prefix(BRANCH_REF_PREFIX).unwrap_or(git_ref)
+}
+
+/// Extract the url of the next page from the value of a `Link` header
+pub(crate) fn next_page_url(link_header: &str) -> Option<&str> {
+    link_header.split(',').find_map(|link| {
+        NEXT_PAGE_LINK_PATTERN
+            .captures(link)
+            .and_then(|capture| capture.get(1))
+            .map(|url| url.as_str())
+    })
+}
+
 impl GithubAPI {
-    pub fn request(&self, method: Method, url: &str) -> RequestBuilder {
-        let full_url = self.base_url.join(url).unwrap(); // TODO: Unwrap yuk
+    fn unauthenticated_request(&self, method: Method, url: &str) -> Result<GithubRequest<'_>> {
+        let full_url = self
+            .base_url
+            .join(url)
+            .with_context(|| format!("Invalid url {}", url))?;
         debug!("{} {}", method, full_url);
-        reqwest::Client::new()
-            .request(method, full_url)
-            .header("Authorization", "token ".to_owned() + &self.token)
-            .header("Accept", "application/vnd.github.v3+json")
+        Ok(
+            GithubRequest::new(&self.retry, self.transport.as_ref(), method, full_url)
+                .header("Accept", "application/vnd.github.v3+json"),
+        )
+    }
+
+    pub fn request(&self, method: Method, url: &str) -> Result<GithubRequest<'_>> {
+        let authorization = match &self.credentials {
+            Credentials::Token(token) => format!("token {}", token),
+            Credentials::App(app) => format!(
+                "token {}",
+                app.installation_token(|jwt| self.fetch_installation_token(app, jwt))?
+            ),
+        };
+        self.unauthenticated_request(method, url)
+            .map(|r| r.header("Authorization", &authorization))
+    }
+
+    /// Exchange the app JWT for a token of the installation on the repo
+    fn fetch_installation_token(&self, app: &GithubApp, jwt: &str) -> Result<InstallationToken> {
+        debug!("Requesting a new installation token for app {}", app.app_id);
+        let installation: Installation = self
+            .unauthenticated_request(
+                Method::GET,
+                &format!("repos/{}/{}/installation", app.repo_owner, app.repo_name),
+            )?
+            .bearer_auth(jwt)
+            .send()
+            .context("Failed to send Github Request")
+            .and_then(|res| {
+                if res.status() == 200 {
+                    res.json().context("Failed to deserialize installation")
+                } else {
+                    Err(anyhow!(
+                        "Github returned unexpected status : {}, is the app installed on {}/{}?",
+                        res.status(),
+                        app.repo_owner,
+                        app.repo_name
+                    ))
+                }
+            })?;
+        self.unauthenticated_request(
+            Method::POST,
+            &format!("app/installations/{}/access_tokens", installation.id),
+        )?
+        .bearer_auth(jwt)
+        // A new token is issued every time, the previous ones staying valid
+        .idempotent()
+        .send()
+        .context("Failed to send Github Request")
+        .and_then(|res| {
+            if res.status() == 201 {
+                res.json()
+                    .context("Failed to deserialize installation token")
+            } else {
+                Err(anyhow!(
+                    "Github returned unexpected status : {}",
+                    res.status()
+                ))
+            }
+        })
+    }
+
+    /// Replace every secret currently used by this client in the text by its masked version
+    pub fn mask_secrets(&self, text: &str) -> String {
+        match &self.credentials {
+            Credentials::Token(token) => mask_token_in(text, token),
+            Credentials::App(app) => app
+                .current_token()
+                .map(|token| mask_token_in(text, &token))
+                .unwrap_or_else(|| text.to_owned()),
+        }
+    }
+
+    /// GET every page of a list endpoint, following the `Link` header
+    pub fn get_all_pages<T: DeserializeOwned>(&self, url: &str) -> Result<Vec<T>> {
+        let mut first_page_url = self
+            .base_url
+            .join(url)
+            .with_context(|| format!("Invalid url {}", url))?;
+        first_page_url
+            .query_pairs_mut()
+            .append_pair("per_page", &self.pagination.page_size.to_string());
+
+        let mut items = Vec::new();
+        let mut page_url = Some(first_page_url.into_string());
+        let mut page_count = 0;
+        while let Some(current_url) = page_url.take() {
+            if page_count >= self.pagination.max_pages {
+                warn!(
+                    "Stopped listing {} after {} pages, results may be incomplete",
+                    url, page_count
+                );
+                break;
+            }
+            page_count += 1;
+            let res = self
+                .request(Method::GET, &current_url)?
+                .send()
+                .context("Failed to send Github Request")?;
+            if res.status() != 200 {
+                return Err(anyhow!(
+                    "Github returned unexpected status : {}",
+                    res.status()
+                ));
+            }
+            page_url = res
+                .headers()
+                .get(LINK)
+                .and_then(|link| link.to_str().ok())
+                .and_then(next_page_url)
+                .map(ToOwned::to_owned);
+            let mut page: Vec<T> = res
+                .json()
+                .with_context(|| format!("Failed to parse Response: {:?}", res))?;
+            items.append(&mut page);
+        }
+        Ok(items)
     }
 
     pub fn find_pr_for_ref(&self, repo_owner: &str, repo_name: &str, git_ref: &str) -> Result<u64> {
@@ -89,52 +420,135 @@ impl GithubAPI {
             });
         }
 
+        let branch = branch_name_from_ref(git_ref);
+
+        debug!("Searching PR with head {}:{}", repo_owner, branch);
+        let head_filter: String =
+            form_urlencoded::byte_serialize(format!("{}:{}", repo_owner, branch).as_bytes())
+                .collect();
+        let filtered_prs: Vec<PullRequestSummary> = self
+            .get_all_pages(&format!(
+                "repos/{}/{}/pulls?state=open&head={}",
+                repo_owner, repo_name, head_filter
+            ))
+            .context("Listing PRs failed")?;
+        if let Some(pr) = filtered_prs.iter().find(|pr| pr.head.commit_ref == branch) {
+            return Ok(pr.number);
+        }
+
+        // The head filter only matches branches of the base repo owner (not forks)
+        debug!("No PR found with head filter, walking through every open PR");
+        self.get_all_pages(&format!(
+            "repos/{}/{}/pulls?state=open&sort=updated&direction=desc",
+            repo_owner, repo_name
+        ))
+        .context("Listing PRs failed")
+        .and_then(|prs: Vec<PullRequestSummary>| {
+            if let Some(pr) = prs.iter().find(|pr| pr.head.commit_ref == branch) {
+                Ok(pr.number)
+            } else {
+                Err(anyhow!("No PRs are matching the branch name {}", branch))
+            }
+        })
+    }
+
+    pub fn get_pull_request(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+    ) -> Result<PullRequest> {
         self.request(
             Method::GET,
-            &format!(
-                "repos/{}/{}/pulls?state=open&sort=updated&direction=desc",
-                repo_owner, repo_name
-            ),
-        )
+            &format!("repos/{}/{}/pulls/{}", repo_owner, repo_name, pr_number),
+        )?
         .send()
-        .context("Failed to send Github Request")
-        .and_then(|mut r| {
-            r.json()
-                .with_context(|| format!("Failed to parse Response: {:?}", r))
+        .context("Retrieving PR failed")
+        .and_then(|res| {
+            if res.status() == 200 {
+                res.json().context("Failed to deserialize PR")
+            } else {
+                Err(anyhow!(
+                    "Github returned unexpected status : {}",
+                    res.status()
+                ))
+            }
         })
+    }
+
+    pub fn find_pr_for_sha(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        sha: &str,
+        preference: PrStatePreference,
+    ) -> Result<u64> {
+        debug!("Searching PRs associated with commit {}", sha);
+        self.get_all_pages(&format!(
+            "repos/{}/{}/commits/{}/pulls",
+            repo_owner, repo_name, sha
+        ))
+        .context("Listing PRs associated with commit failed")
         .and_then(|prs: Vec<PullRequestSummary>| {
-            if let Some(pr) = prs.iter().find(|pr| pr.head.commit_ref == git_ref) {
+            if prs.is_empty() {
+                Err(anyhow!("No PRs are associated with the commit {}", sha))
+            } else if let Some(pr) = select_pr_by_state(&prs, preference) {
                 Ok(pr.number)
             } else {
-                Err(anyhow!("No PRs are matching the branch name"))
+                Err(anyhow!(
+                    "None of the PRs associated with the commit {} are {} (found {:?})",
+                    sha,
+                    preference.to_string().to_lowercase(),
+                    prs.iter().map(|pr| pr.number).collect::<Vec<_>>()
+                ))
             }
         })
     }
 
-    pub fn comment<T: Into<String>>(
+    /// Create the comment at most once: after an ambiguous failure the comments are listed
+    /// before any retry, and the one recognized by `is_created` is returned if found
+    pub fn comment<T: Into<String>, F: Fn(&IssueComment) -> bool>(
         &self,
         repo_owner: &str,
         repo_name: &str,
         issue_number: u64,
         comment: T,
+        is_created: F,
     ) -> Result<IssueComment> {
         let body = CommentCreateRequest {
             body: comment.into(),
         };
 
-        self.request(
-            Method::POST,
-            &format!(
-                "repos/{}/{}/issues/{}/comments",
-                repo_owner, repo_name, issue_number
-            ),
-        )
-        .json(&body)
-        .send()
-        .context("Creating comment failed")
-        .and_then(|mut res| {
+        let created: RefCell<Option<IssueComment>> = RefCell::new(None);
+        let result = self
+            .request(
+                Method::POST,
+                &format!(
+                    "repos/{}/{}/issues/{}/comments",
+                    repo_owner, repo_name, issue_number
+                ),
+            )?
+            .json(&body)
+            .check_processed_before_retry(|| {
+                let found = self
+                    .list_comments(repo_owner, repo_name, issue_number)?
+                    .into_iter()
+                    .find(|c| is_created(c));
+                let is_processed = found.is_some();
+                created.replace(found);
+                Ok(is_processed)
+            })
+            .send();
+        if let Some(comment) = created.into_inner() {
+            debug!("Comment {} was created despite the failure", comment.id);
+            return Ok(comment);
+        }
+        result.context("Creating comment failed").and_then(|res| {
             if res.status() == 201 {
                 res.json().context("Failed to deserialize comment")
+            } else if res.status() == 422 {
+                // e.g. the body is longer than `limit::MAX_COMMENT_LENGTH`
+                Err(anyhow!("Github rejected the comment : {}", res.body))
             } else {
                 Err(anyhow!(
                     "Github returned unexpected status : {}",
@@ -161,13 +575,15 @@ impl GithubAPI {
                 "repos/{}/{}/issues/comments/{}",
                 repo_owner, repo_name, comment_id
             ),
-        )
+        )?
         .json(&body)
         .send()
         .context("Editing comment failed")
-        .and_then(|mut res| {
+        .and_then(|res| {
             if res.status() == 200 {
                 res.json().context("Failed to deserialize comment")
+            } else if res.status() == 422 {
+                Err(anyhow!("Github rejected the comment : {}", res.body))
             } else {
                 Err(anyhow!(
                     "Github returned unexpected status : {}",
@@ -177,24 +593,19 @@ impl GithubAPI {
         })
     }
 
-    pub fn list_comments(
-        &self,
-        repo_owner: &str,
-        repo_name: &str,
-        issue_number: u64,
-    ) -> Result<Vec<IssueComment>> {
+    pub fn delete_comment(&self, repo_owner: &str, repo_name: &str, comment_id: u64) -> Result<()> {
         self.request(
-            Method::GET,
+            Method::DELETE,
             &format!(
-                "repos/{}/{}/issues/{}/comments",
-                repo_owner, repo_name, issue_number
+                "repos/{}/{}/issues/comments/{}",
+                repo_owner, repo_name, comment_id
             ),
-        )
+        )?
         .send()
-        .context("Listing comments failed")
-        .and_then(|mut res| {
-            if res.status() == 200 {
-                res.json().context("Failed to deserialize comments")
+        .context("Deleting comment failed")
+        .and_then(|res| {
+            if res.status() == 204 {
+                Ok(())
             } else {
                 Err(anyhow!(
                     "Github returned unexpected status : {}",
@@ -203,41 +614,208 @@ impl GithubAPI {
             }
         })
     }
+
+    /// Run a GraphQL query, failing if Github reports any error
+    pub fn graphql<V: Serialize, D: DeserializeOwned>(
+        &self,
+        query: &str,
+        variables: V,
+    ) -> Result<D> {
+        let url = graphql_url(&self.base_url)?;
+        self.request(Method::POST, url.as_str())?
+            // Both the queries and the minimize mutation can be sent several times
+            .idempotent()
+            .json(&GraphqlRequest { query, variables })
+            .send()
+            .context("GraphQL request failed")
+            .and_then(|res| {
+                if res.status() == 200 {
+                    res.json().context("Failed to deserialize GraphQL response")
+                } else {
+                    Err(anyhow!(
+                        "Github returned unexpected status : {}",
+                        res.status()
+                    ))
+                }
+            })
+            .and_then(|res: GraphqlResponse<D>| {
+                if !res.errors.is_empty() {
+                    Err(anyhow!(
+                        "GraphQL query failed : {}",
+                        res.errors
+                            .iter()
+                            .map(|e| e.message.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                } else {
+                    res.data
+                        .ok_or_else(|| anyhow!("GraphQL response has no data"))
+                }
+            })
+    }
+
+    /// Collapse the comment, flagging it as outdated
+    pub fn minimize_comment(&self, comment: &IssueComment) -> Result<()> {
+        self.graphql::<_, serde_json::Value>(
+            MINIMIZE_COMMENT_MUTATION,
+            serde_json::json!({ "id": comment.node_id }),
+        )
+        .with_context(|| format!("Minimizing comment {} failed", comment.id))
+        .map(|_| ())
+    }
+
+    /// Keep only the comments that are not minimized yet
+    pub fn filter_not_minimized(&self, comments: Vec<IssueComment>) -> Result<Vec<IssueComment>> {
+        if comments.is_empty() {
+            return Ok(comments);
+        }
+        let ids: Vec<&str> = comments.iter().map(|c| c.node_id.as_str()).collect();
+        let states: MinimizedStateNodes = self
+            .graphql(MINIMIZED_STATE_QUERY, serde_json::json!({ "ids": ids }))
+            .context("Retrieving minimized state of comments failed")?;
+        let minimized: Vec<String> = states
+            .nodes
+            .into_iter()
+            .flatten()
+            .filter(|state| state.is_minimized)
+            .map(|state| state.id)
+            .collect();
+        Ok(comments
+            .into_iter()
+            .filter(|c| !minimized.contains(&c.node_id))
+            .collect())
+    }
+
+    pub fn list_comments(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        issue_number: u64,
+    ) -> Result<Vec<IssueComment>> {
+        self.get_all_pages(&format!(
+            "repos/{}/{}/issues/{}/comments",
+            repo_owner, repo_name, issue_number
+        ))
+        .context("Listing comments failed")
+    }
+}
+
+impl Forge for GithubAPI {
+    fn kind(&self) -> ForgeKind {
+        ForgeKind::Github
+    }
+
+    fn find_pr_for_ref(&self, repo_owner: &str, repo_name: &str, git_ref: &str) -> Result<u64> {
+        GithubAPI::find_pr_for_ref(self, repo_owner, repo_name, git_ref)
+    }
+
+    fn list_comments(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+    ) -> Result<Vec<IssueComment>> {
+        GithubAPI::list_comments(self, repo_owner, repo_name, pr_number)
+    }
+
+    fn comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        body: &str,
+        is_created: &dyn Fn(&IssueComment) -> bool,
+    ) -> Result<IssueComment> {
+        GithubAPI::comment(self, repo_owner, repo_name, pr_number, body, is_created)
+    }
+
+    fn edit_comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        _pr_number: u64,
+        comment_id: u64,
+        body: &str,
+    ) -> Result<IssueComment> {
+        GithubAPI::edit_comment(self, repo_owner, repo_name, comment_id, body)
+    }
+
+    fn delete_comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        _pr_number: u64,
+        comment_id: u64,
+    ) -> Result<()> {
+        GithubAPI::delete_comment(self, repo_owner, repo_name, comment_id)
+    }
+
+    fn max_comment_length(&self) -> usize {
+        MAX_COMMENT_LENGTH
+    }
+
+    fn mask_secrets(&self, text: &str) -> String {
+        GithubAPI::mask_secrets(self, text)
+    }
+
+    fn github(&self) -> Option<&GithubAPI> {
+        Some(self)
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct RepoInfo {
+    pub forge: ForgeKind,
     pub api_url: Url,
+    /// The GitLab subgroups are part of it, e.g. `group/subgroup`
     pub org: String,
     pub name: String,
 }
 
-pub fn get_repo_info_from_url(url: Url) -> Result<RepoInfo> {
+/// Whether the host serves GitLab, either gitlab.com or a self-managed instance named gitlab.*
+fn is_gitlab_host(host: &str) -> bool {
+    host == "gitlab.com" || host.starts_with("gitlab.")
+}
+
+/// The repo of the url, on the given forge or else on the one guessed from the host
+pub fn get_repo_info_from_url(url: Url, forge: Option<ForgeKind>) -> Result<RepoInfo> {
     if url.query().is_some() || url.fragment().is_some() {
         return Err(anyhow!("Url {} has unexpected query args or fragment", url));
     }
+    let forge = forge.unwrap_or_else(|| match url.host_str() {
+        Some(host) if is_gitlab_host(host) => ForgeKind::GitLab,
+        _ => ForgeKind::Github,
+    });
     if let Some(segments) = url.path_segments() {
         let seg_vec = Vec::from_iter(segments);
-        if seg_vec.len() != 2 {
+        if forge == ForgeKind::Github && seg_vec.len() != 2 {
             Err(anyhow!(
                 "Url {} doesn't have the expected 2 path segments (org, repo name)",
                 url
             ))
+        } else if forge == ForgeKind::GitLab && seg_vec.len() < 2 {
+            Err(anyhow!(
+                "Url {} doesn't have the expected path segments (groups, project name)",
+                url
+            ))
         } else if let Some(host) = url.host_str() {
-            let api_url = if host == "github.com" {
-                DEFAULT_GITHUB_API_URL.clone()
-            } else {
-                url.join("/api/v3/")
-                    .with_context(|| format!("Couldnt determine api url for {}", url))?
-            };
-            let repo_name = if seg_vec[1].ends_with(".git") {
-                seg_vec[1][..seg_vec[1].len() - 4].to_owned()
-            } else {
-                seg_vec[1].to_owned()
+            let api_url = match (forge, host) {
+                (ForgeKind::Github, "github.com") => DEFAULT_GITHUB_API_URL.clone(),
+                (ForgeKind::GitLab, "gitlab.com") => DEFAULT_GITLAB_API_URL.clone(),
+                (ForgeKind::Github, _) => url
+                    .join("/api/v3/")
+                    .with_context(|| format!("Couldnt determine api url for {}", url))?,
+                (ForgeKind::GitLab, _) => url
+                    .join("/api/v4/")
+                    .with_context(|| format!("Couldnt determine api url for {}", url))?,
             };
+            let (name, groups) = seg_vec.split_last().unwrap();
+            let repo_name = name.strip_suffix(".git").unwrap_or(name).to_owned();
             Ok(RepoInfo {
+                forge,
                 api_url: api_url,
-                org: seg_vec[0].to_owned(),
+                org: groups.join("/"),
                 name: repo_name,
             })
         } else {
@@ -255,12 +833,13 @@ mod tests {
     fn repo(url: &str) -> Result<RepoInfo> {
         Url::from_str(url)
             .context("Can't parse URL")
-            .and_then(get_repo_info_from_url)
+            .and_then(|url| get_repo_info_from_url(url, None))
     }
 
     #[test]
     fn test_get_repo_info() {
         let good_github_repo = RepoInfo {
+            forge: ForgeKind::Github,
             api_url: Url::from_str("https://api.github.com/").unwrap(),
             org: "thibaultdelor".to_owned(),
             name: "GithubPRCommentator".to_owned(),
@@ -278,6 +857,7 @@ mod tests {
     #[test]
     fn test_get_repo_info_ghe() {
         let good_github_repo = RepoInfo {
+            forge: ForgeKind::Github,
             api_url: Url::from_str("https://my.github.internal/api/v3/").unwrap(),
             org: "thibaultdelor".to_owned(),
             name: "GithubPRCommentator".to_owned(),
@@ -292,6 +872,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_repo_info_gitlab() {
+        assert_eq!(
+            repo("https://gitlab.com/thibaultdelor/GithubPRCommentator.git").unwrap(),
+            RepoInfo {
+                forge: ForgeKind::GitLab,
+                api_url: Url::from_str("https://gitlab.com/api/v4/").unwrap(),
+                org: "thibaultdelor".to_owned(),
+                name: "GithubPRCommentator".to_owned(),
+            }
+        );
+        assert_eq!(
+            repo("https://gitlab.my.company/group/subgroup/GithubPRCommentator").unwrap(),
+            RepoInfo {
+                forge: ForgeKind::GitLab,
+                api_url: Url::from_str("https://gitlab.my.company/api/v4/").unwrap(),
+                org: "group/subgroup".to_owned(),
+                name: "GithubPRCommentator".to_owned(),
+            }
+        );
+        assert!(repo("https://gitlab.com/GithubPRCommentator").is_err());
+    }
+
+    #[test]
+    fn test_get_repo_info_explicit_forge() {
+        let url = Url::from_str("https://git.corp/group/sub/project").unwrap();
+        assert!(get_repo_info_from_url(url.clone(), None).is_err());
+        assert_eq!(
+            get_repo_info_from_url(url, Some(ForgeKind::GitLab)).unwrap(),
+            RepoInfo {
+                forge: ForgeKind::GitLab,
+                api_url: Url::from_str("https://git.corp/api/v4/").unwrap(),
+                org: "group/sub".to_owned(),
+                name: "project".to_owned(),
+            }
+        );
+        assert_eq!(
+            get_repo_info_from_url(
+                Url::from_str("https://git.corp/group/project").unwrap(),
+                Some(ForgeKind::GitLab)
+            )
+            .unwrap()
+            .api_url,
+            Url::from_str("https://git.corp/api/v4/").unwrap()
+        );
+        assert_eq!(
+            get_repo_info_from_url(
+                Url::from_str("https://gitlab.internal/org/repo").unwrap(),
+                Some(ForgeKind::Github)
+            )
+            .unwrap()
+            .api_url,
+            Url::from_str("https://gitlab.internal/api/v3/").unwrap()
+        );
+    }
+
     #[test]
     fn test_unsupported_url() {
         // git url not supported yet
@@ -319,4 +955,119 @@ mod tests {
             Ok(1)
         );
     }
+
+    fn pr_summary(number: u64, state: &str, merged_at: Option<&str>) -> PullRequestSummary {
+        let user_url = "https://api.github.com/users/thibaultdelor";
+        serde_json::from_value(serde_json::json!({
+            "number": number,
+            "state": state,
+            "merged_at": merged_at,
+            "head": {
+                "label": "thibaultdelor:my_branch",
+                "ref": "my_branch",
+                "sha": "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+                "user": {
+                    "login": "thibaultdelor",
+                    "id": 1,
+                    "avatar_url": user_url,
+                    "gravatar_id": "",
+                    "url": user_url,
+                    "html_url": user_url,
+                    "followers_url": user_url,
+                    "following_url": user_url,
+                    "gists_url": user_url,
+                    "starred_url": user_url,
+                    "subscriptions_url": user_url,
+                    "organizations_url": user_url,
+                    "repos_url": user_url,
+                    "events_url": user_url,
+                    "received_events_url": user_url,
+                    "site_admin": false
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_select_pr_by_state() {
+        let prs = vec![
+            pr_summary(1, "closed", Some("2020-01-01T00:00:00Z")),
+            pr_summary(2, "open", None),
+            pr_summary(3, "closed", Some("2020-02-01T00:00:00Z")),
+            pr_summary(4, "closed", None),
+            pr_summary(5, "open", None),
+        ];
+        assert_eq!(
+            select_pr_by_state(&prs, PrStatePreference::Open).map(|pr| pr.number),
+            Some(5)
+        );
+        assert_eq!(
+            select_pr_by_state(&prs, PrStatePreference::Merged).map(|pr| pr.number),
+            Some(3)
+        );
+        assert!(select_pr_by_state(&prs[3..4], PrStatePreference::Open).is_none());
+        assert!(select_pr_by_state(&prs[3..4], PrStatePreference::Merged).is_none());
+    }
+
+    #[test]
+    fn test_graphql_url() {
+        assert_eq!(
+            graphql_url(&DEFAULT_GITHUB_API_URL).unwrap().as_str(),
+            "https://api.github.com/graphql"
+        );
+        assert_eq!(
+            graphql_url(&Url::from_str("https://my.github.internal/api/v3/").unwrap())
+                .unwrap()
+                .as_str(),
+            "https://my.github.internal/api/graphql"
+        );
+    }
+
+    #[test]
+    fn test_mask_token_in() {
+        assert_eq!(
+            mask_token_in("Bad credentials for ghp_abcdefghijkl!", "ghp_abcdefghijkl"),
+            "Bad credentials for gh************kl!"
+        );
+        assert_eq!(mask_token_in("short abc", "abc"), "short ************");
+        assert_eq!(mask_token_in("nothing to mask", ""), "nothing to mask");
+    }
+
+    #[test]
+    fn test_branch_name_from_ref() {
+        assert_eq!(branch_name_from_ref("refs/heads/my_branch"), "my_branch");
+        assert_eq!(
+            branch_name_from_ref("refs/heads/feature/foo"),
+            "feature/foo"
+        );
+        assert_eq!(branch_name_from_ref("my_branch"), "my_branch");
+        assert_eq!(
+            branch_name_from_ref("feature/refs/heads/foo"),
+            "feature/refs/heads/foo"
+        );
+    }
+
+    #[test]
+    fn test_next_page_url() {
+        assert_eq!(
+            next_page_url(
+                "<https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=2>; rel=\"next\", \
+                 <https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=5>; rel=\"last\""
+            ),
+            Some("https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=2")
+        );
+        assert_eq!(
+            next_page_url(
+                "<https://api.github.com/repositories/1/pulls?page=1>; rel=\"prev\", \
+                 <https://api.github.com/repositories/1/pulls?page=3>; rel=\"next\""
+            ),
+            Some("https://api.github.com/repositories/1/pulls?page=3")
+        );
+        assert_eq!(
+            next_page_url("<https://api.github.com/repositories/1/pulls?page=1>; rel=\"first\""),
+            None
+        );
+        assert_eq!(next_page_url(""), None);
+    }
 }
@@ -0,0 +1,473 @@
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Method, StatusCode};
+use serde::Serialize;
+use url::Url;
+
+use super::transport::{HttpRequest, HttpResponse, Transport};
+
+const RATE_LIMIT_REMAINING: &str = "x-ratelimit-remaining";
+const RATE_LIMIT_RESET: &str = "x-ratelimit-reset";
+
+/// Define how failed requests to Github are retried
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts of a single request, the first one included
+    pub max_attempts: u32,
+    /// Time after which a request is not retried anymore, waiting included
+    pub deadline: Duration,
+    /// Delay before the first retry, doubled on every retry
+    pub base_delay: Duration,
+    /// Upper bound of the backoff delay
+    pub max_delay: Duration,
+    /// Delay after a secondary rate limit which doesn't tell how long to wait
+    pub secondary_rate_limit_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            deadline: Duration::from_secs(5 * 60),
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            // Github recommends waiting at least a minute
+            secondary_rate_limit_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter, between half and the full delay of the attempt
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let millis = exponential.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2, millis + 1))
+    }
+}
+
+/// Why a request should be retried
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum RetryReason {
+    /// Primary or secondary rate limit, the request was rejected without being processed
+    RateLimited,
+    /// Server error, the request may have been processed
+    ServerError,
+    /// The request couldn't be sent or the response not received
+    Transport,
+}
+
+/// Whether sending the request twice has the same effect as sending it once
+fn is_idempotent(method: &Method) -> bool {
+    method != Method::POST
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Github may only tell about a secondary rate limit in the message of the response
+fn is_secondary_rate_limit(status: StatusCode, body: &str) -> bool {
+    let body = body.to_lowercase();
+    status == StatusCode::FORBIDDEN
+        && (body.contains("secondary rate limit") || body.contains("abuse"))
+}
+
+fn retry_reason(status: StatusCode, headers: &HeaderMap, body: &str) -> Option<RetryReason> {
+    let rate_limited = status == StatusCode::TOO_MANY_REQUESTS
+        || (status == StatusCode::FORBIDDEN
+            && (headers.contains_key(RETRY_AFTER)
+                || header_u64(headers, RATE_LIMIT_REMAINING) == Some(0)))
+        || is_secondary_rate_limit(status, body);
+    if rate_limited {
+        Some(RetryReason::RateLimited)
+    } else if status.is_server_error() {
+        Some(RetryReason::ServerError)
+    } else {
+        None
+    }
+}
+
+/// The delay requested by Github through `Retry-After` or `X-RateLimit-Reset`
+fn requested_delay(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    if let Some(seconds) = header_u64(headers, RETRY_AFTER.as_str()) {
+        return Some(Duration::from_secs(seconds));
+    }
+    if header_u64(headers, RATE_LIMIT_REMAINING) == Some(0) {
+        let now = now.duration_since(UNIX_EPOCH).ok()?;
+        return header_u64(headers, RATE_LIMIT_RESET).map(|reset| {
+            // One more second as the reset time is truncated
+            Duration::from_secs(reset.saturating_sub(now.as_secs()) + 1)
+        });
+    }
+    None
+}
+
+/// A request to Github, sent according to the retry policy
+pub struct GithubRequest<'a> {
+    policy: &'a RetryPolicy,
+    transport: &'a dyn Transport,
+    request: Result<HttpRequest>,
+    idempotent: bool,
+    /// Tell whether a non idempotent request was processed despite the failure
+    processed_check: Option<Box<dyn FnMut() -> Result<bool> + 'a>>,
+}
+
+impl<'a> GithubRequest<'a> {
+    pub fn new(
+        policy: &'a RetryPolicy,
+        transport: &'a dyn Transport,
+        method: Method,
+        url: Url,
+    ) -> Self {
+        GithubRequest {
+            idempotent: is_idempotent(&method),
+            processed_check: None,
+            policy,
+            transport,
+            request: Ok(HttpRequest {
+                method,
+                url,
+                headers: Vec::new(),
+                body: None,
+            }),
+        }
+    }
+
+    pub fn json<T: Serialize + ?Sized>(self, json: &T) -> Self {
+        GithubRequest {
+            request: self.request.and_then(|mut request| {
+                request.body =
+                    Some(serde_json::to_string(json).context("Failed to serialize request")?);
+                request
+                    .headers
+                    .push(("Content-Type".to_owned(), "application/json".to_owned()));
+                Ok(request)
+            }),
+            ..self
+        }
+    }
+
+    pub fn header(self, name: &'static str, value: &str) -> Self {
+        GithubRequest {
+            request: self.request.map(|mut request| {
+                request.headers.push((name.to_owned(), value.to_owned()));
+                request
+            }),
+            ..self
+        }
+    }
+
+    pub fn bearer_auth<T: std::fmt::Display>(self, token: T) -> Self {
+        self.header("Authorization", &format!("Bearer {}", token))
+    }
+
+    /// Mark a POST request as safe to send several times (e.g. a GraphQL query)
+    pub fn idempotent(self) -> Self {
+        GithubRequest {
+            idempotent: true,
+            ..self
+        }
+    }
+
+    /// Retry a non idempotent request after an ambiguous failure if the check tells it wasn't
+    /// processed. The check is also run when giving up, to let the caller find what was processed.
+    pub fn check_processed_before_retry<F>(self, check: F) -> Self
+    where
+        F: FnMut() -> Result<bool> + 'a,
+    {
+        GithubRequest {
+            processed_check: Some(Box::new(check)),
+            ..self
+        }
+    }
+
+    /// Send the request, retrying on rate limits, server and transport errors until the policy
+    /// gives up, in which case the last response or error is returned.
+    /// Non idempotent requests are only retried when rejected by a rate limit, or when the
+    /// processed check tells they weren't processed.
+    pub fn send(mut self) -> Result<HttpResponse> {
+        let request = self.request?;
+        let policy = self.policy;
+        let method = &request.method;
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let (reason, delay, result) = match self.transport.send(&request) {
+                Ok(res) => match retry_reason(res.status(), res.headers(), &res.body) {
+                    Some(reason) => {
+                        let delay =
+                            requested_delay(res.headers(), SystemTime::now()).or_else(|| {
+                                if is_secondary_rate_limit(res.status(), &res.body) {
+                                    Some(policy.secondary_rate_limit_delay)
+                                } else {
+                                    None
+                                }
+                            });
+                        (reason, delay, Ok(res))
+                    }
+                    None => return Ok(res),
+                },
+                Err(e) => (RetryReason::Transport, None, Err(e)),
+            };
+            let outcome = match &result {
+                Ok(res) => format!("status {}", res.status()),
+                Err(e) => format!("error {:#}", e),
+            };
+            let delay = delay.unwrap_or_else(|| policy.backoff(attempt));
+
+            let ambiguous = !self.idempotent && reason != RetryReason::RateLimited;
+            let give_up = match self.processed_check.as_mut() {
+                _ if !ambiguous => None,
+                None => Some(format!("as {} requests may have been processed", method)),
+                Some(check) => {
+                    debug!("Checking whether the {} request was processed", method);
+                    match check() {
+                        Ok(true) => Some("as it was processed".to_owned()),
+                        Ok(false) => None,
+                        Err(e) => Some(format!(
+                            "as checking whether it was processed failed : {:#}",
+                            e
+                        )),
+                    }
+                }
+            }
+            .or_else(|| {
+                if attempt >= policy.max_attempts {
+                    Some(format!("after {} attempts", attempt))
+                } else if start.elapsed() + delay > policy.deadline {
+                    Some(format!(
+                        "as retrying in {:?} would exceed the {:?} deadline",
+                        delay, policy.deadline
+                    ))
+                } else {
+                    None
+                }
+            });
+            if let Some(give_up) = give_up {
+                warn!(
+                    "{} request failed with {} ({:?}), not retrying {}",
+                    method, outcome, reason, give_up
+                );
+                return result.context("No response from the server");
+            }
+
+            warn!(
+                "{} request failed with {} ({:?}), retrying in {:?} (attempt {}/{})",
+                method,
+                outcome,
+                reason,
+                delay,
+                attempt + 1,
+                policy.max_attempts
+            );
+            thread::sleep(delay);
+            attempt += 1;
+            debug!("Retrying {} request", method);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::transport::ScriptedTransport;
+    use reqwest::header::HeaderValue;
+
+    fn headers(values: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_retry_reason() {
+        assert_eq!(retry_reason(StatusCode::OK, &headers(&[]), ""), None);
+        assert_eq!(retry_reason(StatusCode::NOT_FOUND, &headers(&[]), ""), None);
+        assert_eq!(retry_reason(StatusCode::FORBIDDEN, &headers(&[]), ""), None);
+        assert_eq!(
+            retry_reason(
+                StatusCode::FORBIDDEN,
+                &headers(&[("retry-after", "60")]),
+                ""
+            ),
+            Some(RetryReason::RateLimited)
+        );
+        assert_eq!(
+            retry_reason(
+                StatusCode::FORBIDDEN,
+                &headers(&[("x-ratelimit-remaining", "0")]),
+                ""
+            ),
+            Some(RetryReason::RateLimited)
+        );
+        assert_eq!(
+            retry_reason(StatusCode::TOO_MANY_REQUESTS, &headers(&[]), ""),
+            Some(RetryReason::RateLimited)
+        );
+        assert_eq!(
+            retry_reason(StatusCode::BAD_GATEWAY, &headers(&[]), ""),
+            Some(RetryReason::ServerError)
+        );
+        assert_eq!(
+            retry_reason(
+                StatusCode::FORBIDDEN,
+                &headers(&[]),
+                r#"{"message": "You have exceeded a secondary rate limit. Please wait a few minutes before you try again."}"#
+            ),
+            Some(RetryReason::RateLimited)
+        );
+        assert_eq!(
+            retry_reason(
+                StatusCode::FORBIDDEN,
+                &headers(&[]),
+                r#"{"message": "You have triggered an abuse detection mechanism."}"#
+            ),
+            Some(RetryReason::RateLimited)
+        );
+        assert_eq!(
+            retry_reason(
+                StatusCode::FORBIDDEN,
+                &headers(&[]),
+                r#"{"message": "Resource not accessible by integration"}"#
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_requested_delay() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(requested_delay(&headers(&[]), now), None);
+        assert_eq!(
+            requested_delay(&headers(&[("retry-after", "30")]), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            requested_delay(
+                &headers(&[
+                    ("x-ratelimit-remaining", "0"),
+                    ("x-ratelimit-reset", "1060")
+                ]),
+                now
+            ),
+            Some(Duration::from_secs(61))
+        );
+        assert_eq!(
+            requested_delay(
+                &headers(&[
+                    ("x-ratelimit-remaining", "10"),
+                    ("x-ratelimit-reset", "1060")
+                ]),
+                now
+            ),
+            None
+        );
+    }
+
+    fn send(transport: &ScriptedTransport, method: Method) -> Result<HttpResponse> {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        GithubRequest::new(
+            &policy,
+            transport,
+            method,
+            Url::parse("https://api.github.com/repos/o/r").unwrap(),
+        )
+        .send()
+    }
+
+    #[test]
+    fn test_send_retries_idempotent_requests() {
+        let transport = ScriptedTransport::new();
+        transport
+            .respond(Method::GET, "/repos/o/r", 502, serde_json::Value::Null)
+            .fail(Method::GET, "/repos/o/r", "connection reset")
+            .respond(Method::GET, "/repos/o/r", 200, serde_json::json!({}));
+        assert_eq!(send(&transport, Method::GET).unwrap().status(), 200);
+        assert!(transport.is_done());
+
+        transport
+            .respond(Method::GET, "/repos/o/r", 502, serde_json::Value::Null)
+            .respond(Method::GET, "/repos/o/r", 502, serde_json::Value::Null)
+            .respond(Method::GET, "/repos/o/r", 502, serde_json::Value::Null);
+        assert_eq!(send(&transport, Method::GET).unwrap().status(), 502);
+        assert!(transport.is_done());
+    }
+
+    #[test]
+    fn test_send_doesnt_retry_ambiguous_post() {
+        let transport = ScriptedTransport::new();
+        transport.respond(Method::POST, "/repos/o/r", 502, serde_json::Value::Null);
+        assert_eq!(send(&transport, Method::POST).unwrap().status(), 502);
+        assert!(transport.is_done());
+
+        // Rejected by a rate limit, so not processed
+        transport.respond_with(Method::POST, "/repos/o/r", |_| {
+            let mut res = HttpResponse::new(StatusCode::TOO_MANY_REQUESTS, "");
+            res.headers
+                .insert(RETRY_AFTER, HeaderValue::from_static("0"));
+            Ok(res)
+        });
+        transport.respond(Method::POST, "/repos/o/r", 201, serde_json::json!({}));
+        assert_eq!(send(&transport, Method::POST).unwrap().status(), 201);
+        assert!(transport.is_done());
+    }
+
+    #[test]
+    fn test_send_waits_after_secondary_rate_limit() {
+        let transport = ScriptedTransport::new();
+        transport
+            .respond(
+                Method::POST,
+                "/repos/o/r",
+                403,
+                serde_json::json!({ "message": "You have exceeded a secondary rate limit." }),
+            )
+            .respond(Method::POST, "/repos/o/r", 201, serde_json::json!({}));
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            secondary_rate_limit_delay: Duration::from_millis(50),
+            ..RetryPolicy::default()
+        };
+        let start = Instant::now();
+        let res = GithubRequest::new(
+            &policy,
+            &transport,
+            Method::POST,
+            Url::parse("https://api.github.com/repos/o/r").unwrap(),
+        )
+        .send()
+        .unwrap();
+        assert_eq!(res.status(), 201);
+        assert!(transport.is_done());
+        assert!(start.elapsed() >= policy.secondary_rate_limit_delay);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..10 {
+            let delay = policy.backoff(attempt);
+            let full = Duration::from_secs(2u64.pow(attempt - 1)).min(policy.max_delay);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+    }
+}
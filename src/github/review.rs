@@ -0,0 +1,375 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use log::debug;
+use regex::Regex;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use super::GithubAPI;
+
+lazy_static! {
+    /// `path:line: message` or `path:line:column: message`, as printed by most linters
+    static ref FINDING_PATTERN: Regex =
+        Regex::new(r"^(?P<path>[^:\s][^:]*):(?P<line>\d+)(?::\d+)?:\s*(?P<message>.+)$").unwrap();
+    static ref HUNK_HEADER_PATTERN: Regex =
+        Regex::new(r"^@@ -\d+(?:,\d+)? \+(?P<start>\d+)(?:,\d+)? @@").unwrap();
+}
+
+/// A message attached to a line of a file
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Finding {
+    pub path: String,
+    pub line: u64,
+    pub message: String,
+}
+
+/// Parse findings, one per line, lines not matching `path:line[:column]: message` are ignored
+pub fn parse_findings(content: &str) -> Vec<Finding> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let capture = FINDING_PATTERN.captures(line.trim_end())?;
+            Some(Finding {
+                path: capture["path"].trim_start_matches("./").to_owned(),
+                line: u64::from_str(&capture["line"]).ok()?,
+                message: capture["message"].to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// The lines of the new version of the file that appear in the patch and can be commented
+pub fn commentable_lines(patch: &str) -> HashSet<u64> {
+    let mut lines = HashSet::new();
+    let mut current_line: Option<u64> = None;
+    for patch_line in patch.lines() {
+        if let Some(capture) = HUNK_HEADER_PATTERN.captures(patch_line) {
+            current_line = u64::from_str(&capture["start"]).ok();
+            continue;
+        }
+        if let Some(line) = current_line {
+            match patch_line.chars().next() {
+                Some('-') => {}
+                Some('\\') => {} // "\ No newline at end of file"
+                _ => {
+                    lines.insert(line);
+                    current_line = Some(line + 1);
+                }
+            }
+        }
+    }
+    lines
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct PullRequestFile {
+    pub filename: String,
+    /// Absent for binary files or diffs too large
+    #[serde(default)]
+    pub patch: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ReviewCommentRequest {
+    pub path: String,
+    pub line: u64,
+    pub side: &'static str,
+    pub body: String,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ReviewCreateRequest {
+    pub body: String,
+    pub event: &'static str,
+    pub comments: Vec<ReviewCommentRequest>,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+struct ReviewUpdateRequest {
+    body: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Review {
+    pub id: u64,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ReviewComment {
+    pub id: u64,
+}
+
+/// Split the findings between the ones that can be posted inline and the others
+pub fn split_findings(
+    findings: Vec<Finding>,
+    files: &[PullRequestFile],
+) -> (Vec<Finding>, Vec<Finding>) {
+    let lines_by_file: HashMap<&str, HashSet<u64>> = files
+        .iter()
+        .map(|f| {
+            (
+                f.filename.as_str(),
+                f.patch
+                    .as_deref()
+                    .map(commentable_lines)
+                    .unwrap_or_default(),
+            )
+        })
+        .collect();
+    findings.into_iter().partition(|finding| {
+        lines_by_file
+            .get(finding.path.as_str())
+            .map(|lines| lines.contains(&finding.line))
+            .unwrap_or(false)
+    })
+}
+
+/// The review body, listing the findings that couldn't be posted inline
+pub fn review_body(summary: &str, outside_diff: &[Finding]) -> String {
+    let mut body = summary.trim_end().to_owned();
+    if !outside_diff.is_empty() {
+        if !body.is_empty() {
+            body.push_str("\n\n");
+        }
+        body.push_str("Findings outside of the diff:\n");
+        for finding in outside_diff {
+            body.push_str(&format!(
+                "\n- `{}:{}`: {}",
+                finding.path, finding.line, finding.message
+            ));
+        }
+    }
+    body
+}
+
+impl GithubAPI {
+    pub fn list_pr_files(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+    ) -> Result<Vec<PullRequestFile>> {
+        self.get_all_pages(&format!(
+            "repos/{}/{}/pulls/{}/files",
+            repo_owner, repo_name, pr_number
+        ))
+        .context("Listing PR files failed")
+    }
+
+    /// Post a review, the findings must be on lines of the diff
+    pub fn create_review(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        body: String,
+        findings: &[Finding],
+    ) -> Result<Review> {
+        let request = ReviewCreateRequest {
+            body,
+            event: "COMMENT",
+            comments: findings
+                .iter()
+                .map(|finding| ReviewCommentRequest {
+                    path: finding.path.clone(),
+                    line: finding.line,
+                    side: "RIGHT",
+                    body: finding.message.clone(),
+                })
+                .collect(),
+        };
+        debug!(
+            "Creating review with {} inline comments on PR#{}",
+            request.comments.len(),
+            pr_number
+        );
+        self.request(
+            Method::POST,
+            &format!(
+                "repos/{}/{}/pulls/{}/reviews",
+                repo_owner, repo_name, pr_number
+            ),
+        )?
+        .json(&request)
+        .send()
+        .context("Creating review failed")
+        .and_then(|res| {
+            if res.status() == 200 {
+                res.json().context("Failed to deserialize review")
+            } else {
+                Err(anyhow!(
+                    "Github returned unexpected status : {}",
+                    res.status()
+                ))
+            }
+        })
+    }
+
+    pub fn list_reviews(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+    ) -> Result<Vec<Review>> {
+        self.get_all_pages(&format!(
+            "repos/{}/{}/pulls/{}/reviews",
+            repo_owner, repo_name, pr_number
+        ))
+        .context("Listing reviews failed")
+    }
+
+    pub fn update_review_body<T: Into<String>>(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        review_id: u64,
+        body: T,
+    ) -> Result<Review> {
+        self.request(
+            Method::PUT,
+            &format!(
+                "repos/{}/{}/pulls/{}/reviews/{}",
+                repo_owner, repo_name, pr_number, review_id
+            ),
+        )?
+        .json(&ReviewUpdateRequest { body: body.into() })
+        .send()
+        .context("Editing review failed")
+        .and_then(|res| {
+            if res.status() == 200 {
+                res.json().context("Failed to deserialize review")
+            } else {
+                Err(anyhow!(
+                    "Github returned unexpected status : {}",
+                    res.status()
+                ))
+            }
+        })
+    }
+
+    pub fn list_review_comments(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        review_id: u64,
+    ) -> Result<Vec<ReviewComment>> {
+        self.get_all_pages(&format!(
+            "repos/{}/{}/pulls/{}/reviews/{}/comments",
+            repo_owner, repo_name, pr_number, review_id
+        ))
+        .context("Listing review comments failed")
+    }
+
+    pub fn delete_review_comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        comment_id: u64,
+    ) -> Result<()> {
+        self.request(
+            Method::DELETE,
+            &format!(
+                "repos/{}/{}/pulls/comments/{}",
+                repo_owner, repo_name, comment_id
+            ),
+        )?
+        .send()
+        .context("Deleting review comment failed")
+        .and_then(|res| {
+            if res.status() == 204 {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "Github returned unexpected status : {}",
+                    res.status()
+                ))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finding(path: &str, line: u64, message: &str) -> Finding {
+        Finding {
+            path: path.to_owned(),
+            line,
+            message: message.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_parse_findings() {
+        let content = "src/main.rs:12: unused variable `x`\n\
+                       ./src/github/mod.rs:3:10: missing docs\n\
+                       warning: 2 warnings emitted\n\
+                       \n";
+        assert_eq!(
+            parse_findings(content),
+            vec![
+                finding("src/main.rs", 12, "unused variable `x`"),
+                finding("src/github/mod.rs", 3, "missing docs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_commentable_lines() {
+        let patch = "@@ -1,4 +1,5 @@\n \
+                     use std::fs;\n\
+                     -use std::io;\n\
+                     +use std::io::{self, Read};\n\
+                     +use std::str::FromStr;\n \
+                     \n\
+                     @@ -20,2 +21,2 @@ fn main() {\n\
+                     -    old();\n\
+                     +    new();\n\
+                     \\ No newline at end of file";
+        let mut lines: Vec<u64> = commentable_lines(patch).into_iter().collect();
+        lines.sort();
+        assert_eq!(lines, vec![1, 2, 3, 4, 21]);
+    }
+
+    #[test]
+    fn test_split_findings() {
+        let files = vec![
+            PullRequestFile {
+                filename: "src/main.rs".to_owned(),
+                patch: Some("@@ -10,2 +10,3 @@\n a\n+b\n c".to_owned()),
+            },
+            PullRequestFile {
+                filename: "logo.png".to_owned(),
+                patch: None,
+            },
+        ];
+        let (inline, outside) = split_findings(
+            vec![
+                finding("src/main.rs", 11, "in diff"),
+                finding("src/main.rs", 42, "outside diff"),
+                finding("logo.png", 1, "binary"),
+                finding("README.md", 1, "untouched file"),
+            ],
+            &files,
+        );
+        assert_eq!(inline, vec![finding("src/main.rs", 11, "in diff")]);
+        assert_eq!(outside.len(), 3);
+    }
+
+    #[test]
+    fn test_review_body() {
+        assert_eq!(review_body("Lint report\n", &[]), "Lint report");
+        assert_eq!(
+            review_body("", &[finding("README.md", 1, "typo")]),
+            "Findings outside of the diff:\n\n- `README.md:1`: typo"
+        );
+    }
+}
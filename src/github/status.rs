@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString, EnumVariantNames};
+
+use super::GithubAPI;
+
+#[derive(
+    Serialize, Debug, EnumString, EnumVariantNames, Display, PartialEq, Eq, Clone, Copy, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitState {
+    Error,
+    Failure,
+    Pending,
+    Success,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct StatusCreateRequest {
+    pub state: CommitState,
+    /// Distinguish this status from the ones set by other systems
+    pub context: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct CommitStatus {
+    pub id: u64,
+    pub state: String,
+    pub context: String,
+}
+
+impl GithubAPI {
+    pub fn create_status(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        sha: &str,
+        status: &StatusCreateRequest,
+    ) -> Result<CommitStatus> {
+        debug!(
+            "Setting status {} of {} on commit {}",
+            status.state, status.context, sha
+        );
+        self.request(
+            Method::POST,
+            &format!("repos/{}/{}/statuses/{}", repo_owner, repo_name, sha),
+        )?
+        .json(status)
+        .send()
+        .context("Creating commit status failed")
+        .and_then(|res| {
+            if res.status() == 201 {
+                res.json().context("Failed to deserialize commit status")
+            } else {
+                Err(anyhow!(
+                    "Github returned unexpected status : {}",
+                    res.status()
+                ))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_create_request() {
+        let status = StatusCreateRequest {
+            state: CommitState::Pending,
+            context: "lint".to_owned(),
+            description: None,
+            target_url: Some("https://ci.example.com/build/42".to_owned()),
+        };
+        assert_eq!(
+            serde_json::to_value(&status).unwrap(),
+            serde_json::json!({
+                "state": "pending",
+                "context": "lint",
+                "target_url": "https://ci.example.com/build/42"
+            })
+        );
+    }
+}
@@ -0,0 +1,538 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use regex::Regex;
+use reqwest::header::LINK;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use url::{form_urlencoded, Url};
+
+use crate::forge::{Forge, ForgeKind};
+use crate::github::retry::{GithubRequest, RetryPolicy};
+use crate::github::transport::Transport;
+use crate::github::{
+    branch_name_from_ref, mask_token_in, next_page_url, CommentCreateRequest, IssueComment,
+    Pagination,
+};
+
+/// The maximum number of characters of a note body
+pub const MAX_NOTE_LENGTH: usize = 1_000_000;
+
+lazy_static! {
+    pub static ref DEFAULT_GITLAB_API_URL: Url =
+        Url::from_str("https://gitlab.com/api/v4/").unwrap();
+    pub static ref MR_BRANCH_GITLAB_PATTERN: Regex =
+        Regex::new(r"^refs/merge-requests/(\d+)/(?:head|merge)$").unwrap();
+}
+
+/// A comment of a MR, or an event on it (e.g. a push) for the system notes
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Note {
+    pub id: u64,
+    pub body: String,
+    #[serde(default)]
+    pub system: bool,
+}
+
+// The api to retrieve the list of MR doesn't return all the fields of the MR
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct MergeRequestSummary {
+    /// The number of the MR in its project, e.g. 1 for !1
+    pub iid: u64,
+    /// The project the MR is merged into
+    pub project_id: u64,
+    /// The project of the source branch, a fork for the MRs from forks
+    pub source_project_id: u64,
+    pub source_branch: String,
+}
+
+pub struct GitlabAPI {
+    pub base_url: Url,
+    /// A personal, project or group access token with the `api` scope
+    pub token: String,
+    pub pagination: Pagination,
+    pub retry: RetryPolicy,
+    /// Send the requests, e.g. `transport::ReqwestTransport`
+    pub transport: Box<dyn Transport>,
+}
+
+impl fmt::Debug for GitlabAPI {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "GitlabAPI {{ base_url: '{}',  token: '{}', pagination: {:?}, retry: {:?} }}",
+            self.base_url,
+            mask_token_in(&self.token, &self.token),
+            self.pagination,
+            self.retry
+        )
+    }
+}
+
+/// The id of the project in the api, its url-encoded path (e.g. `group%2Fsubgroup%2Fproject`)
+fn project_id(repo_owner: &str, repo_name: &str) -> String {
+    form_urlencoded::byte_serialize(format!("{}/{}", repo_owner, repo_name).as_bytes()).collect()
+}
+
+/// The url of the web interface, the api being served under `/api/v4/`
+fn web_url(base_url: &Url) -> Result<Url> {
+    if base_url.path().trim_end_matches('/').ends_with("/api/v4") {
+        base_url
+            .join("../../")
+            .with_context(|| format!("Couldnt determine web url for {}", base_url))
+    } else {
+        Ok(base_url.clone())
+    }
+}
+
+impl GitlabAPI {
+    pub fn request(&self, method: Method, url: &str) -> Result<GithubRequest<'_>> {
+        let full_url = self
+            .base_url
+            .join(url)
+            .with_context(|| format!("Invalid url {}", url))?;
+        debug!("{} {}", method, full_url);
+        Ok(
+            GithubRequest::new(&self.retry, self.transport.as_ref(), method, full_url)
+                .header("PRIVATE-TOKEN", &self.token),
+        )
+    }
+
+    /// GET every page of a list endpoint, following the `Link` header
+    pub fn get_all_pages<T: DeserializeOwned>(&self, url: &str) -> Result<Vec<T>> {
+        let mut first_page_url = self
+            .base_url
+            .join(url)
+            .with_context(|| format!("Invalid url {}", url))?;
+        first_page_url
+            .query_pairs_mut()
+            .append_pair("per_page", &self.pagination.page_size.to_string());
+
+        let mut items = Vec::new();
+        let mut page_url = Some(first_page_url.into_string());
+        let mut page_count = 0;
+        while let Some(current_url) = page_url.take() {
+            if page_count >= self.pagination.max_pages {
+                warn!(
+                    "Stopped listing {} after {} pages, results may be incomplete",
+                    url, page_count
+                );
+                break;
+            }
+            page_count += 1;
+            let res = self
+                .request(Method::GET, &current_url)?
+                .send()
+                .context("Failed to send GitLab Request")?;
+            if res.status() != 200 {
+                return Err(anyhow!(
+                    "GitLab returned unexpected status : {}",
+                    res.status()
+                ));
+            }
+            page_url = res
+                .headers()
+                .get(LINK)
+                .and_then(|link| link.to_str().ok())
+                .and_then(next_page_url)
+                .map(ToOwned::to_owned);
+            let mut page: Vec<T> = res
+                .json()
+                .with_context(|| format!("Failed to parse Response: {:?}", res))?;
+            items.append(&mut page);
+        }
+        Ok(items)
+    }
+
+    /// The link to the note in the web interface
+    fn note_url(&self, repo_owner: &str, repo_name: &str, mr_iid: u64, note_id: u64) -> String {
+        web_url(&self.base_url)
+            .map(|web_url| {
+                format!(
+                    "{}{}/{}/-/merge_requests/{}#note_{}",
+                    web_url, repo_owner, repo_name, mr_iid, note_id
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    fn issue_comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        mr_iid: u64,
+        note: Note,
+    ) -> IssueComment {
+        IssueComment {
+            id: note.id,
+            node_id: String::new(),
+            html_url: self.note_url(repo_owner, repo_name, mr_iid, note.id),
+            body: note.body,
+        }
+    }
+
+    pub fn find_mr_for_ref(&self, repo_owner: &str, repo_name: &str, git_ref: &str) -> Result<u64> {
+        if let Some(capture) = MR_BRANCH_GITLAB_PATTERN.captures(git_ref) {
+            debug!("Extracting MR number from branch name [{}]", git_ref);
+            return u64::from_str(&capture[1]).with_context(|| {
+                // In practice should never happen
+                format!(
+                    "Reference {} identified as MR but failing to parse",
+                    git_ref
+                )
+            });
+        }
+
+        let branch = branch_name_from_ref(git_ref);
+
+        debug!("Searching MR with source branch {}", branch);
+        let branch_filter: String = form_urlencoded::byte_serialize(branch.as_bytes()).collect();
+        // The source branch filter also matches the MRs from forks, whose branches only share
+        // the name. Of the others the most recent one wins.
+        self.get_all_pages(&format!(
+            "projects/{}/merge_requests?state=opened&source_branch={}",
+            project_id(repo_owner, repo_name),
+            branch_filter
+        ))
+        .context("Listing MRs failed")
+        .and_then(|mrs: Vec<MergeRequestSummary>| {
+            mrs.iter()
+                .filter(|mr| mr.source_branch == branch && mr.source_project_id == mr.project_id)
+                .map(|mr| mr.iid)
+                .max()
+                .ok_or_else(|| anyhow!("No MRs are matching the branch name {}", branch))
+        })
+    }
+
+    /// The notes written by users, oldest first
+    pub fn list_notes(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        mr_iid: u64,
+    ) -> Result<Vec<IssueComment>> {
+        self.get_all_pages(&format!(
+            "projects/{}/merge_requests/{}/notes?sort=asc&order_by=created_at",
+            project_id(repo_owner, repo_name),
+            mr_iid
+        ))
+        .context("Listing notes failed")
+        .map(|notes: Vec<Note>| {
+            notes
+                .into_iter()
+                .filter(|note| !note.system)
+                .map(|note| self.issue_comment(repo_owner, repo_name, mr_iid, note))
+                .collect()
+        })
+    }
+
+    /// Create the note at most once, see `GithubAPI::comment`
+    pub fn create_note<T: Into<String>, F: Fn(&IssueComment) -> bool>(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        mr_iid: u64,
+        note: T,
+        is_created: F,
+    ) -> Result<IssueComment> {
+        let body = CommentCreateRequest { body: note.into() };
+
+        let created: RefCell<Option<IssueComment>> = RefCell::new(None);
+        let result = self
+            .request(
+                Method::POST,
+                &format!(
+                    "projects/{}/merge_requests/{}/notes",
+                    project_id(repo_owner, repo_name),
+                    mr_iid
+                ),
+            )?
+            .json(&body)
+            .check_processed_before_retry(|| {
+                let found = self
+                    .list_notes(repo_owner, repo_name, mr_iid)?
+                    .into_iter()
+                    .find(|c| is_created(c));
+                let is_processed = found.is_some();
+                created.replace(found);
+                Ok(is_processed)
+            })
+            .send();
+        if let Some(note) = created.into_inner() {
+            debug!("Note {} was created despite the failure", note.id);
+            return Ok(note);
+        }
+        result.context("Creating note failed").and_then(|res| {
+            if res.status() == 201 {
+                res.json()
+                    .context("Failed to deserialize note")
+                    .map(|note| self.issue_comment(repo_owner, repo_name, mr_iid, note))
+            } else {
+                Err(anyhow!(
+                    "GitLab returned unexpected status : {}",
+                    res.status()
+                ))
+            }
+        })
+    }
+
+    pub fn edit_note<T: Into<String>>(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        mr_iid: u64,
+        note_id: u64,
+        note: T,
+    ) -> Result<IssueComment> {
+        let body = CommentCreateRequest { body: note.into() };
+
+        self.request(
+            Method::PUT,
+            &format!(
+                "projects/{}/merge_requests/{}/notes/{}",
+                project_id(repo_owner, repo_name),
+                mr_iid,
+                note_id
+            ),
+        )?
+        .json(&body)
+        .send()
+        .context("Editing note failed")
+        .and_then(|res| {
+            if res.status() == 200 {
+                res.json()
+                    .context("Failed to deserialize note")
+                    .map(|note| self.issue_comment(repo_owner, repo_name, mr_iid, note))
+            } else {
+                Err(anyhow!(
+                    "GitLab returned unexpected status : {}",
+                    res.status()
+                ))
+            }
+        })
+    }
+
+    pub fn delete_note(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        mr_iid: u64,
+        note_id: u64,
+    ) -> Result<()> {
+        self.request(
+            Method::DELETE,
+            &format!(
+                "projects/{}/merge_requests/{}/notes/{}",
+                project_id(repo_owner, repo_name),
+                mr_iid,
+                note_id
+            ),
+        )?
+        .send()
+        .context("Deleting note failed")
+        .and_then(|res| {
+            if res.status() == 204 {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "GitLab returned unexpected status : {}",
+                    res.status()
+                ))
+            }
+        })
+    }
+}
+
+impl Forge for GitlabAPI {
+    fn kind(&self) -> ForgeKind {
+        ForgeKind::GitLab
+    }
+
+    fn find_pr_for_ref(&self, repo_owner: &str, repo_name: &str, git_ref: &str) -> Result<u64> {
+        self.find_mr_for_ref(repo_owner, repo_name, git_ref)
+    }
+
+    fn list_comments(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+    ) -> Result<Vec<IssueComment>> {
+        self.list_notes(repo_owner, repo_name, pr_number)
+    }
+
+    fn comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        body: &str,
+        is_created: &dyn Fn(&IssueComment) -> bool,
+    ) -> Result<IssueComment> {
+        self.create_note(repo_owner, repo_name, pr_number, body, is_created)
+    }
+
+    fn edit_comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        comment_id: u64,
+        body: &str,
+    ) -> Result<IssueComment> {
+        self.edit_note(repo_owner, repo_name, pr_number, comment_id, body)
+    }
+
+    fn delete_comment(
+        &self,
+        repo_owner: &str,
+        repo_name: &str,
+        pr_number: u64,
+        comment_id: u64,
+    ) -> Result<()> {
+        self.delete_note(repo_owner, repo_name, pr_number, comment_id)
+    }
+
+    fn max_comment_length(&self) -> usize {
+        MAX_NOTE_LENGTH
+    }
+
+    fn mask_secrets(&self, text: &str) -> String {
+        mask_token_in(text, &self.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::transport::ScriptedTransport;
+    use serde_json::json;
+
+    const NOTES: &str = "/api/v4/projects/group%2Fsubgroup%2Fproject/merge_requests/3/notes";
+
+    fn scripted_api() -> (ScriptedTransport, GitlabAPI) {
+        let transport = ScriptedTransport::new();
+        let api = GitlabAPI {
+            base_url: DEFAULT_GITLAB_API_URL.clone(),
+            token: "glpat-token".to_owned(),
+            pagination: Pagination::default(),
+            retry: RetryPolicy::default(),
+            transport: Box::new(transport.clone()),
+        };
+        (transport, api)
+    }
+
+    #[test]
+    fn test_gitlab_mr_branch_pattern() {
+        assert!(!MR_BRANCH_GITLAB_PATTERN.is_match("refs/heads/my_branch"));
+        assert!(!MR_BRANCH_GITLAB_PATTERN.is_match("refs/pull/1/head"));
+        assert_eq!(
+            u32::from_str(
+                &MR_BRANCH_GITLAB_PATTERN
+                    .captures("refs/merge-requests/12/head")
+                    .unwrap()[1]
+            ),
+            Ok(12)
+        );
+    }
+
+    #[test]
+    fn test_web_url() {
+        assert_eq!(
+            web_url(&DEFAULT_GITLAB_API_URL).unwrap().as_str(),
+            "https://gitlab.com/"
+        );
+        assert_eq!(
+            web_url(&Url::from_str("https://my.company/gitlab/api/v4/").unwrap())
+                .unwrap()
+                .as_str(),
+            "https://my.company/gitlab/"
+        );
+    }
+
+    #[test]
+    fn test_find_mr_for_ref() {
+        let (transport, api) = scripted_api();
+        assert_eq!(
+            api.find_mr_for_ref("group", "project", "refs/merge-requests/7/head")
+                .unwrap(),
+            7
+        );
+        transport.respond(
+            Method::GET,
+            "/api/v4/projects/group%2Fproject/merge_requests",
+            200,
+            json!([
+                {"iid": 4, "project_id": 1, "source_project_id": 1, "source_branch": "feature/foo"},
+                {"iid": 5, "project_id": 1, "source_project_id": 1, "source_branch": "feature/foo"},
+                {"iid": 6, "project_id": 1, "source_project_id": 1, "source_branch": "feature/foo-bar"},
+                // From a fork with a branch of the same name
+                {"iid": 7, "project_id": 1, "source_project_id": 2, "source_branch": "feature/foo"}
+            ]),
+        );
+        assert_eq!(
+            api.find_mr_for_ref("group", "project", "refs/heads/feature/foo")
+                .unwrap(),
+            5
+        );
+        let request = &transport.requests()[0];
+        assert_eq!(request.header("PRIVATE-TOKEN"), Some("glpat-token"));
+        assert!(request
+            .url
+            .as_str()
+            .contains("state=opened&source_branch=feature%2Ffoo"));
+    }
+
+    #[test]
+    fn test_list_notes() {
+        let (transport, api) = scripted_api();
+        transport.respond(
+            Method::GET,
+            NOTES,
+            200,
+            json!([
+                {"id": 1, "body": "added 1 commit", "system": true},
+                {"id": 2, "body": "LGTM", "system": false}
+            ]),
+        );
+        assert_eq!(
+            api.list_notes("group/subgroup", "project", 3).unwrap(),
+            vec![IssueComment {
+                id: 2,
+                node_id: String::new(),
+                html_url: "https://gitlab.com/group/subgroup/project/-/merge_requests/3#note_2"
+                    .to_owned(),
+                body: "LGTM".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_edit_and_delete_note() {
+        let (transport, api) = scripted_api();
+        transport
+            .respond(
+                Method::PUT,
+                &format!("{}/2", NOTES),
+                200,
+                json!({"id": 2, "body": "Build succeeded"}),
+            )
+            .respond(
+                Method::DELETE,
+                &format!("{}/2", NOTES),
+                204,
+                serde_json::Value::Null,
+            );
+        let edited = api
+            .edit_note("group/subgroup", "project", 3, 2, "Build succeeded")
+            .unwrap();
+        assert_eq!(edited.body, "Build succeeded");
+        api.delete_note("group/subgroup", "project", 3, 2).unwrap();
+        assert!(transport.is_done());
+    }
+}
@@ -0,0 +1,43 @@
+//! Comment on Github PRs (or GitLab MRs with `gitlab::GitlabAPI`) from CI, e.g.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use pr_commentator::github::transport::ReqwestTransport;
+//! use pr_commentator::github::{Credentials, GithubAPI, Pagination, DEFAULT_GITHUB_API_URL};
+//! use pr_commentator::{CommentOverwriteMode, Commentator};
+//!
+//! let api = GithubAPI {
+//!     base_url: DEFAULT_GITHUB_API_URL.clone(),
+//!     credentials: Credentials::Token("token".to_owned()),
+//!     pagination: Pagination::default(),
+//!     retry: Default::default(),
+//!     transport: Box::new(ReqwestTransport::default()),
+//! };
+//! let outcome = Commentator::new(api)
+//!     .repo("thibaultdelor", "GithubPRCommentator")
+//!     .pr_for_ref("refs/heads/my_branch")
+//!     .upsert("Build succeeded", CommentOverwriteMode::Always)?;
+//! if let Some(comment) = outcome.comment() {
+//!     println!("{}", comment.html_url);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod ci;
+pub mod commentator;
+#[cfg(feature = "fake-server")]
+pub mod fake;
+pub mod forge;
+pub mod github;
+pub mod gitlab;
+pub mod report;
+pub mod source;
+pub mod template;
+pub mod token;
+
+pub use commentator::{
+    CommentOutcome, CommentOverwriteMode, Commentator, Operation, OversizeStrategy, Plan,
+    PlannedAction, PlannedGist, PostedComment, PrReference, UpsertPlan,
+};
+pub use source::CommentSource;
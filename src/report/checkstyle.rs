@@ -0,0 +1,86 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+
+use super::{Issue, Report, Severity};
+
+fn severity(severity: Option<&str>) -> Severity {
+    match severity {
+        Some("error") => Severity::Error,
+        Some("info") | Some("ignore") => Severity::Note,
+        _ => Severity::Warning,
+    }
+}
+
+/// Parse a Checkstyle XML report (also produced by eslint, ktlint, detekt, ...)
+pub fn parse(content: &str) -> Result<Report> {
+    let document = roxmltree::Document::parse(content).context("Invalid Checkstyle report")?;
+    let root = document.root_element();
+    if !root.has_tag_name("checkstyle") {
+        return Err(anyhow!(
+            "Invalid Checkstyle report, unexpected root element <{}>",
+            root.tag_name().name()
+        ));
+    }
+    let issues = root
+        .children()
+        .filter(|n| n.has_tag_name("file"))
+        .flat_map(|file| {
+            let path = file.attribute("name").map(ToOwned::to_owned);
+            file.children()
+                .filter(|n| n.has_tag_name("error"))
+                .map(move |error| Issue {
+                    severity: severity(error.attribute("severity")),
+                    path: path.clone(),
+                    line: error.attribute("line").and_then(|l| u64::from_str(l).ok()),
+                    message: error.attribute("message").unwrap_or_default().to_owned(),
+                    rule: error.attribute("source").map(ToOwned::to_owned),
+                })
+        })
+        .collect();
+    Ok(Report {
+        tool: "Checkstyle".to_owned(),
+        issues,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_checkstyle() {
+        let report = parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <checkstyle version="8.0">
+              <file name="src/Main.java">
+                <error line="3" column="1" severity="error" message="Missing javadoc" source="JavadocType"/>
+                <error line="10" severity="info" message="Line &gt; 100"/>
+              </file>
+              <file name="src/Empty.java"/>
+            </checkstyle>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            report.issues,
+            vec![
+                Issue {
+                    severity: Severity::Error,
+                    path: Some("src/Main.java".to_owned()),
+                    line: Some(3),
+                    message: "Missing javadoc".to_owned(),
+                    rule: Some("JavadocType".to_owned()),
+                },
+                Issue {
+                    severity: Severity::Note,
+                    path: Some("src/Main.java".to_owned()),
+                    line: Some(10),
+                    message: "Line > 100".to_owned(),
+                    rule: None,
+                },
+            ]
+        );
+        assert!(parse("<testsuite/>").is_err());
+        assert!(parse("not xml").is_err());
+    }
+}
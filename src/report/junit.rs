@@ -0,0 +1,105 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use roxmltree::Node;
+
+use super::{Issue, Report, Severity};
+
+fn testcase_issue(testcase: Node, outcome: Node) -> Issue {
+    let name = match (testcase.attribute("classname"), testcase.attribute("name")) {
+        (Some(class), Some(name)) => format!("{}::{}", class, name),
+        (None, Some(name)) => name.to_owned(),
+        (Some(class), None) => class.to_owned(),
+        (None, None) => "<unnamed test>".to_owned(),
+    };
+    let details = outcome
+        .attribute("message")
+        .or_else(|| outcome.text())
+        .map(str::trim)
+        .filter(|d| !d.is_empty());
+    Issue {
+        severity: Severity::Error,
+        path: testcase.attribute("file").map(ToOwned::to_owned),
+        line: testcase
+            .attribute("line")
+            .and_then(|l| u64::from_str(l).ok()),
+        message: match details {
+            Some(details) => format!("{}: {}", name, details),
+            None => name,
+        },
+        rule: Some(outcome.tag_name().name().to_owned()),
+    }
+}
+
+/// Parse a JUnit XML report, every failed or errored test case is an error
+pub fn parse(content: &str) -> Result<Report> {
+    let document = roxmltree::Document::parse(content).context("Invalid JUnit report")?;
+    let root = document.root_element();
+    if !root.has_tag_name("testsuites") && !root.has_tag_name("testsuite") {
+        return Err(anyhow!(
+            "Invalid JUnit report, unexpected root element <{}>",
+            root.tag_name().name()
+        ));
+    }
+    let testcases: Vec<Node> = root
+        .descendants()
+        .filter(|n| n.has_tag_name("testcase"))
+        .collect();
+    let issues = testcases
+        .iter()
+        .flat_map(|testcase| {
+            testcase
+                .children()
+                .filter(|n| n.has_tag_name("failure") || n.has_tag_name("error"))
+                .map(move |outcome| testcase_issue(*testcase, outcome))
+        })
+        .collect();
+    Ok(Report {
+        tool: format!("Tests ({} run)", testcases.len()),
+        issues,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_junit() {
+        let report = parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <testsuites>
+              <testsuite name="github" tests="3">
+                <testcase classname="github::tests" name="test_ok"/>
+                <testcase classname="github::tests" name="test_ko" file="src/github/mod.rs" line="12">
+                  <failure message="assertion failed: left == right"/>
+                </testcase>
+                <testcase name="test_panic"><error>thread panicked</error></testcase>
+                <testcase name="test_skipped"><skipped/></testcase>
+              </testsuite>
+            </testsuites>"#,
+        )
+        .unwrap();
+        assert_eq!(report.tool, "Tests (4 run)");
+        assert_eq!(
+            report.issues,
+            vec![
+                Issue {
+                    severity: Severity::Error,
+                    path: Some("src/github/mod.rs".to_owned()),
+                    line: Some(12),
+                    message: "github::tests::test_ko: assertion failed: left == right".to_owned(),
+                    rule: Some("failure".to_owned()),
+                },
+                Issue {
+                    severity: Severity::Error,
+                    path: None,
+                    line: None,
+                    message: "test_panic: thread panicked".to_owned(),
+                    rule: Some("error".to_owned()),
+                },
+            ]
+        );
+        assert!(parse("<checkstyle/>").is_err());
+    }
+}
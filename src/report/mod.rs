@@ -0,0 +1,243 @@
+pub mod checkstyle;
+pub mod junit;
+pub mod sarif;
+
+use std::fmt;
+
+use strum_macros::{Display, EnumIter};
+use url::Url;
+
+/// Severity of an issue, ordered from the most to the least severe
+#[derive(Debug, Display, EnumIter, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    fn icon(self) -> &'static str {
+        match self {
+            Severity::Error => ":x:",
+            Severity::Warning => ":warning:",
+            Severity::Note => ":information_source:",
+        }
+    }
+}
+
+/// A single problem reported by a tool, optionally attached to a file line
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Issue {
+    pub severity: Severity,
+    pub path: Option<String>,
+    pub line: Option<u64>,
+    pub message: String,
+    pub rule: Option<String>,
+}
+
+/// The issues reported by a tool, whatever the format it came from
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Report {
+    pub tool: String,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn count(&self, severity: Severity) -> usize {
+        self.issues
+            .iter()
+            .filter(|i| i.severity == severity)
+            .count()
+    }
+}
+
+/// Where the files of the report can be browsed
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct LinkContext {
+    /// The html url of the repo (e.g. `https://github.com/org/repo`)
+    pub repo_url: Url,
+    /// The commit the report was generated for, the PR head
+    pub sha: String,
+    /// Prefix stripped from absolute paths to make them relative to the repo root
+    pub workspace: Option<String>,
+}
+
+impl LinkContext {
+    /// The path relative to the repo root, `None` if it can't be determined
+    fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let path = path.trim_start_matches("file://");
+        let relative = match &self.workspace {
+            Some(workspace)
+                if path.starts_with(&format!("{}/", workspace.trim_end_matches('/'))) =>
+            {
+                &path[workspace.trim_end_matches('/').len()..]
+            }
+            _ if path.starts_with('/') => return None,
+            _ => path,
+        };
+        let relative = relative.trim_start_matches("./").trim_start_matches('/');
+        if relative.is_empty() {
+            None
+        } else {
+            Some(relative)
+        }
+    }
+
+    fn link(&self, path: &str, line: Option<u64>) -> Option<String> {
+        let relative = self.relative_path(path)?;
+        let mut url = format!(
+            "{}/blob/{}/{}",
+            self.repo_url.as_str().trim_end_matches('/'),
+            self.sha,
+            relative
+        );
+        if let Some(line) = line {
+            url.push_str(&format!("#L{}", line));
+        }
+        Some(url)
+    }
+}
+
+/// Escape the characters breaking a markdown table cell
+fn escape_cell(text: &str) -> String {
+    text.trim()
+        .replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
+
+struct Location<'a> {
+    issue: &'a Issue,
+    links: &'a LinkContext,
+}
+
+impl<'a> fmt::Display for Location<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.issue.path, self.issue.line) {
+            (None, _) => write!(f, "-"),
+            (Some(path), line) => {
+                let label = self.links.relative_path(path).unwrap_or(path);
+                let label = match line {
+                    Some(line) => format!("{}:{}", label, line),
+                    None => label.to_owned(),
+                };
+                match self.links.link(path, line) {
+                    Some(url) => write!(f, "[`{}`]({})", label, url),
+                    None => write!(f, "`{}`", label),
+                }
+            }
+        }
+    }
+}
+
+/// Render the report as a markdown summary table followed by the list of issues
+pub fn render_markdown(report: &Report, links: &LinkContext) -> String {
+    use strum::IntoEnumIterator;
+
+    let mut markdown = format!("### {}\n\n", report.tool);
+    if report.issues.is_empty() {
+        markdown.push_str(":white_check_mark: No issues found\n");
+        return markdown;
+    }
+
+    markdown.push_str("| Severity | Count |\n|---|---|\n");
+    for severity in Severity::iter() {
+        markdown.push_str(&format!(
+            "| {} {} | {} |\n",
+            severity.icon(),
+            severity,
+            report.count(severity)
+        ));
+    }
+
+    let mut issues: Vec<&Issue> = report.issues.iter().collect();
+    issues.sort_by_key(|i| (i.severity, i.path.clone(), i.line));
+    markdown.push_str("\n| Severity | Location | Message |\n|---|---|---|\n");
+    for issue in issues {
+        let message = match &issue.rule {
+            Some(rule) => format!("{} (`{}`)", escape_cell(&issue.message), rule),
+            None => escape_cell(&issue.message),
+        };
+        markdown.push_str(&format!(
+            "| {} | {} | {} |\n",
+            issue.severity.icon(),
+            Location { issue, links },
+            message
+        ));
+    }
+    markdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn links() -> LinkContext {
+        LinkContext {
+            repo_url: Url::from_str("https://github.com/thibaultdelor/GithubPRCommentator")
+                .unwrap(),
+            sha: "abc123".to_owned(),
+            workspace: Some("/home/ci/build/".to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let links = links();
+        assert_eq!(links.relative_path("src/main.rs"), Some("src/main.rs"));
+        assert_eq!(links.relative_path("./src/main.rs"), Some("src/main.rs"));
+        assert_eq!(
+            links.relative_path("file:///home/ci/build/src/main.rs"),
+            Some("src/main.rs")
+        );
+        assert_eq!(links.relative_path("/usr/lib/rust/lib.rs"), None);
+    }
+
+    #[test]
+    fn test_render_empty_report() {
+        let report = Report {
+            tool: "clippy".to_owned(),
+            issues: vec![],
+        };
+        assert_eq!(
+            render_markdown(&report, &links()),
+            "### clippy\n\n:white_check_mark: No issues found\n"
+        );
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        let report = Report {
+            tool: "clippy".to_owned(),
+            issues: vec![
+                Issue {
+                    severity: Severity::Warning,
+                    path: Some("/home/ci/build/src/main.rs".to_owned()),
+                    line: Some(12),
+                    message: "unused | variable\nsecond line".to_owned(),
+                    rule: Some("unused_variables".to_owned()),
+                },
+                Issue {
+                    severity: Severity::Error,
+                    path: None,
+                    line: None,
+                    message: "build failed".to_owned(),
+                    rule: None,
+                },
+            ],
+        };
+        assert_eq!(
+            render_markdown(&report, &links()),
+            "### clippy\n\n\
+             | Severity | Count |\n|---|---|\n\
+             | :x: Error | 1 |\n\
+             | :warning: Warning | 1 |\n\
+             | :information_source: Note | 0 |\n\
+             \n| Severity | Location | Message |\n|---|---|---|\n\
+             | :x: | - | build failed |\n\
+             | :warning: | [`src/main.rs:12`](https://github.com/thibaultdelor/GithubPRCommentator/blob/abc123/src/main.rs#L12) \
+             | unused \\| variable<br>second line (`unused_variables`) |\n"
+        );
+    }
+}
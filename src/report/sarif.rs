@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::{Issue, Report, Severity};
+
+// Only the subset of SARIF 2.1.0 needed to build the report is deserialized
+#[derive(Deserialize, Debug)]
+struct SarifLog {
+    runs: Vec<Run>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Run {
+    tool: Tool,
+    #[serde(default)]
+    results: Vec<SarifResult>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Tool {
+    driver: Driver,
+}
+
+#[derive(Deserialize, Debug)]
+struct Driver {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SarifResult {
+    #[serde(default)]
+    rule_id: Option<String>,
+    #[serde(default)]
+    level: Option<String>,
+    message: Message,
+    #[serde(default)]
+    locations: Vec<Location>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Message {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    #[serde(default)]
+    physical_location: Option<PhysicalLocation>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PhysicalLocation {
+    #[serde(default)]
+    artifact_location: Option<ArtifactLocation>,
+    #[serde(default)]
+    region: Option<Region>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtifactLocation {
+    #[serde(default)]
+    uri: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Region {
+    #[serde(default)]
+    start_line: Option<u64>,
+}
+
+fn severity(level: Option<&str>) -> Severity {
+    // SARIF defaults to warning when no level is given
+    match level {
+        Some("error") => Severity::Error,
+        Some("note") | Some("none") => Severity::Note,
+        _ => Severity::Warning,
+    }
+}
+
+/// Parse a SARIF log, the results of every run are merged in a single report
+pub fn parse(content: &str) -> Result<Report> {
+    let log: SarifLog = serde_json::from_str(content).context("Invalid SARIF report")?;
+    let tool = log
+        .runs
+        .iter()
+        .map(|run| run.tool.driver.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let issues = log
+        .runs
+        .into_iter()
+        .flat_map(|run| run.results)
+        .map(|result| {
+            let physical_location = result
+                .locations
+                .into_iter()
+                .find_map(|l| l.physical_location);
+            let (path, line) = match physical_location {
+                Some(location) => (
+                    location.artifact_location.and_then(|a| a.uri),
+                    location.region.and_then(|r| r.start_line),
+                ),
+                None => (None, None),
+            };
+            Issue {
+                severity: severity(result.level.as_deref()),
+                path,
+                line,
+                message: result.message.text,
+                rule: result.rule_id,
+            }
+        })
+        .collect();
+    Ok(Report {
+        tool: if tool.is_empty() {
+            "SARIF".to_owned()
+        } else {
+            tool
+        },
+        issues,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sarif() {
+        let report = parse(
+            r#"{
+              "version": "2.1.0",
+              "runs": [{
+                "tool": {"driver": {"name": "clippy"}},
+                "results": [
+                  {
+                    "ruleId": "clippy::needless_return",
+                    "level": "error",
+                    "message": {"text": "unneeded `return` statement"},
+                    "locations": [{
+                      "physicalLocation": {
+                        "artifactLocation": {"uri": "src/main.rs"},
+                        "region": {"startLine": 42, "startColumn": 5}
+                      }
+                    }]
+                  },
+                  {"message": {"text": "no location"}}
+                ]
+              }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(report.tool, "clippy");
+        assert_eq!(
+            report.issues,
+            vec![
+                Issue {
+                    severity: Severity::Error,
+                    path: Some("src/main.rs".to_owned()),
+                    line: Some(42),
+                    message: "unneeded `return` statement".to_owned(),
+                    rule: Some("clippy::needless_return".to_owned()),
+                },
+                Issue {
+                    severity: Severity::Warning,
+                    path: None,
+                    line: None,
+                    message: "no location".to_owned(),
+                    rule: None,
+                },
+            ]
+        );
+        assert!(parse("{}").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use url::Url;
+
+use crate::github::PullRequest;
+use crate::report::{checkstyle, junit, render_markdown, sarif, LinkContext, Report};
+use crate::template::{self, TemplateContext};
+
+/// Where the content of the comment comes from
+#[derive(Debug)]
+pub enum CommentSource {
+    StrArg {
+        comment: String,
+    },
+    Standard(io::Stdin),
+    File(fs::File),
+    /// A SARIF report, rendered as markdown
+    Sarif(fs::File),
+    /// A Checkstyle XML report, rendered as markdown
+    Checkstyle(fs::File),
+    /// A JUnit XML report, rendered as markdown
+    JUnit(fs::File),
+    /// A handlebars template, rendered with the variables, the data file and the PR details
+    Template {
+        template: fs::File,
+        partials: Vec<(String, fs::File)>,
+        data: Option<fs::File>,
+        vars: BTreeMap<String, String>,
+        /// The environment variables allowed in the template
+        env: BTreeMap<String, String>,
+    },
+}
+
+fn read_file(file: &mut fs::File) -> Result<String> {
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer)
+        .map(|_| buffer)
+        .context("Failed to read file")
+}
+
+impl CommentSource {
+    /// Whether the PR details are needed to retrieve the comment
+    pub fn needs_pull_request(&self) -> bool {
+        match self {
+            CommentSource::Sarif(_)
+            | CommentSource::Checkstyle(_)
+            | CommentSource::JUnit(_)
+            | CommentSource::Template { .. } => true,
+            CommentSource::StrArg { .. } | CommentSource::Standard(_) | CommentSource::File(_) => {
+                false
+            }
+        }
+    }
+
+    pub fn retrieve(&mut self, pull_request: Option<&PullRequest>) -> Result<String> {
+        match self {
+            CommentSource::StrArg { comment } => Ok(comment.clone()),
+            CommentSource::Standard(stdin) => {
+                debug!("Reading stdin for comment");
+                let mut buffer = String::new();
+                stdin
+                    .read_to_string(&mut buffer)
+                    .map(|_| buffer)
+                    .context("Failed to read comment from stdin")
+            }
+            CommentSource::File(file) => {
+                debug!("Reading file for comment");
+                read_file(file).context("Failed to read comment from file")
+            }
+            CommentSource::Sarif(file) => {
+                debug!("Reading SARIF report");
+                let report = read_file(file)
+                    .and_then(|content| sarif::parse(&content))
+                    .context("Failed to read SARIF report")?;
+                render_report(&report, pull_request)
+            }
+            CommentSource::Checkstyle(file) => {
+                debug!("Reading Checkstyle report");
+                let report = read_file(file)
+                    .and_then(|content| checkstyle::parse(&content))
+                    .context("Failed to read Checkstyle report")?;
+                render_report(&report, pull_request)
+            }
+            CommentSource::JUnit(file) => {
+                debug!("Reading JUnit report");
+                let report = read_file(file)
+                    .and_then(|content| junit::parse(&content))
+                    .context("Failed to read JUnit report")?;
+                render_report(&report, pull_request)
+            }
+            CommentSource::Template {
+                template,
+                partials,
+                data,
+                vars,
+                env,
+            } => {
+                debug!("Rendering comment template");
+                let pull_request = pull_request
+                    .ok_or_else(|| anyhow!("PR details are required to render a template"))?;
+                let template = read_file(template).context("Failed to read template")?;
+                let partials = partials
+                    .iter_mut()
+                    .map(|(name, file)| {
+                        read_file(file)
+                            .map(|partial| (name.clone(), partial))
+                            .with_context(|| format!("Failed to read partial template {}", name))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let data = match data {
+                    Some(file) => read_file(file)
+                        .and_then(|content| serde_json::from_str(&content).context("Invalid JSON"))
+                        .context("Failed to read template data")?,
+                    None => serde_json::Value::Null,
+                };
+                let context = TemplateContext {
+                    env: env.clone(),
+                    vars: vars.clone(),
+                    data,
+                    pr: pull_request.into(),
+                };
+                template::render(&template, &partials, &context)
+            }
+        }
+    }
+}
+
+/// Render the report with links to the files at the PR head
+fn render_report(report: &Report, pull_request: Option<&PullRequest>) -> Result<String> {
+    let pull_request =
+        pull_request.ok_or_else(|| anyhow!("PR details are required to render a report"))?;
+    let repo_url = pull_request
+        .base
+        .repo
+        .as_ref()
+        .ok_or_else(|| anyhow!("PR#{} has no base repo", pull_request.number))
+        .and_then(|repo| {
+            Url::from_str(&repo.html_url)
+                .with_context(|| format!("Invalid repo url {}", repo.html_url))
+        })?;
+    let links = LinkContext {
+        repo_url,
+        sha: pull_request.head.sha.clone(),
+        workspace: std::env::current_dir()
+            .ok()
+            .map(|dir| dir.to_string_lossy().into_owned()),
+    };
+    Ok(render_markdown(report, &links))
+}
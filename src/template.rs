@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use handlebars::Handlebars;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::ci::Environment;
+use crate::github::PullRequest;
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct BranchContext {
+    pub branch: String,
+    pub sha: String,
+}
+
+/// The PR details exposed to the templates as `pr`
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct PrContext {
+    pub number: u64,
+    pub title: String,
+    pub author: String,
+    pub url: String,
+    pub base: BranchContext,
+    pub head: BranchContext,
+}
+
+impl From<&PullRequest> for PrContext {
+    fn from(pr: &PullRequest) -> PrContext {
+        PrContext {
+            number: pr.number,
+            title: pr.title.clone(),
+            author: pr.user.login.clone(),
+            url: pr.html_url.clone(),
+            base: BranchContext {
+                branch: pr.base.branch.clone(),
+                sha: pr.base.sha.clone(),
+            },
+            head: BranchContext {
+                branch: pr.head.branch.clone(),
+                sha: pr.head.sha.clone(),
+            },
+        }
+    }
+}
+
+/// The variables available in the templates:
+/// - `env`: the environment variables explicitly allowed, see [`env_context`]
+/// - `vars`: the variables given on the command line
+/// - `data`: the content of the JSON data file, `null` if absent
+/// - `pr`: the PR being commented
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TemplateContext {
+    pub env: BTreeMap<String, String>,
+    pub vars: BTreeMap<String, String>,
+    pub data: Value,
+    pub pr: PrContext,
+}
+
+/// Read the allowed environment variables, the absent ones are skipped.
+/// The rendered comments being public, no other variable is available to the templates.
+pub fn env_context(names: &[String], env: &dyn Environment) -> BTreeMap<String, String> {
+    names
+        .iter()
+        .filter_map(|name| env.var(name).map(|value| (name.clone(), value)))
+        .collect()
+}
+
+/// Parse a `key=value` variable, the value may contain `=`
+pub fn parse_var(var: &str) -> Result<(String, String)> {
+    let mut parts = var.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_owned(), value.to_owned()))
+        }
+        _ => Err(anyhow!("Invalid variable `{}`, expecting `key=value`", var)),
+    }
+}
+
+/// Render a handlebars template, the output being markdown nothing is html escaped
+pub fn render(
+    template: &str,
+    partials: &[(String, String)],
+    context: &TemplateContext,
+) -> Result<String> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(handlebars::no_escape);
+    for (name, partial) in partials {
+        handlebars
+            .register_partial(name, partial)
+            .with_context(|| format!("Invalid partial template `{}`", name))?;
+    }
+    handlebars
+        .render_template(template, context)
+        .context("Failed to render template")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn context() -> TemplateContext {
+        TemplateContext {
+            env: vec![("CI".to_owned(), "true".to_owned())]
+                .into_iter()
+                .collect(),
+            vars: vec![("build".to_owned(), "42".to_owned())]
+                .into_iter()
+                .collect(),
+            data: serde_json::json!({ "failures": ["test_a", "test_b"] }),
+            pr: PrContext {
+                number: 7,
+                title: "Add templates".to_owned(),
+                author: "octocat".to_owned(),
+                url: "https://github.com/thibaultdelor/GithubPRCommentator/pull/7".to_owned(),
+                base: BranchContext {
+                    branch: "master".to_owned(),
+                    sha: "abc".to_owned(),
+                },
+                head: BranchContext {
+                    branch: "templates".to_owned(),
+                    sha: "def".to_owned(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_var() {
+        assert_eq!(
+            parse_var("key=a=b").unwrap(),
+            ("key".to_owned(), "a=b".to_owned())
+        );
+        assert_eq!(
+            parse_var("key=").unwrap(),
+            ("key".to_owned(), "".to_owned())
+        );
+        assert!(parse_var("key").is_err());
+        assert!(parse_var("=value").is_err());
+    }
+
+    #[test]
+    fn test_env_context_only_has_allowed_vars() {
+        let env: HashMap<&str, &str> = vec![
+            ("CI", "true"),
+            ("BUILD_URL", "https://ci/42"),
+            ("AWS_SECRET_ACCESS_KEY", "hunter2"),
+        ]
+        .into_iter()
+        .collect();
+        let mut context = context();
+        context.env = env_context(&["CI".to_owned(), "MISSING".to_owned()], &env);
+        assert_eq!(
+            context.env,
+            vec![("CI".to_owned(), "true".to_owned())]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            render(
+                "{{env.CI}}|{{env.AWS_SECRET_ACCESS_KEY}}|{{env.BUILD_URL}}",
+                &[],
+                &context
+            )
+            .unwrap(),
+            "true||"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let template = "Build {{vars.build}} of #{{pr.number}} by @{{pr.author}} \
+                        ({{pr.head.branch}} -> {{pr.base.branch}})\n\
+                        {{#if env.CI}}{{> failures}}{{/if}}{{#if env.MISSING}}never{{/if}}";
+        let partials = vec![(
+            "failures".to_owned(),
+            "{{#each data.failures}}- `{{this}}`\n{{/each}}".to_owned(),
+        )];
+        assert_eq!(
+            render(template, &partials, &context()).unwrap(),
+            "Build 42 of #7 by @octocat (templates -> master)\n- `test_a`\n- `test_b`\n"
+        );
+    }
+
+    #[test]
+    fn test_render_invalid_template() {
+        assert!(render("{{#if}}", &[], &context()).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use url::Url;
+
+use crate::ci::Environment;
+
+/// Environment variables checked for a token, in order
+pub const TOKEN_ENV_VARS: [&str; 2] = ["GITHUB_TOKEN", "GH_TOKEN"];
+/// Environment variables checked for a GitLab token, in order
+pub const GITLAB_TOKEN_ENV_VARS: [&str; 1] = ["GITLAB_TOKEN"];
+
+/// Retrieve the token, checking the sources in the following order:
+/// 1. the token given on the command line
+/// 2. the content of the token file
+/// 3. the environment variables, e.g. `TOKEN_ENV_VARS` for Github
+/// 4. the password returned by `git credential fill` for the api host
+pub fn resolve_token(
+    arg_token: Option<&str>,
+    token_file: Option<&str>,
+    env_vars: &[&str],
+    env: &dyn Environment,
+    api_url: &Url,
+) -> Result<String> {
+    if let Some(token) = arg_token {
+        debug!("Using token from the command line");
+        return Ok(token.to_owned());
+    }
+    if let Some(token_file) = token_file {
+        debug!("Reading token from file {}", token_file);
+        return fs::read_to_string(token_file)
+            .with_context(|| format!("Failed to read token file {}", token_file))
+            .and_then(|content| non_empty_token(&content))
+            .with_context(|| format!("Invalid token file {}", token_file));
+    }
+    if let Some((var, token)) = env_vars
+        .iter()
+        .find_map(|var| env.var(var).map(|token| (var, token)))
+    {
+        debug!("Using token from environment variable {}", var);
+        return non_empty_token(&token);
+    }
+    debug!("Asking git credential helpers for a token");
+    token_from_git_credential(api_url).with_context(|| {
+        format!(
+            "No token found on the command line, in the token file, \
+             in the {} environment variables or from git credential helpers",
+            env_vars.join("/")
+        )
+    })
+}
+
+fn non_empty_token(content: &str) -> Result<String> {
+    let token = content.trim();
+    if token.is_empty() {
+        Err(anyhow!("Token is empty"))
+    } else {
+        Ok(token.to_owned())
+    }
+}
+
+/// The host git credentials are stored for, `api.github.com` being served by `github.com`
+fn credential_host(api_url: &Url) -> Option<String> {
+    api_url.host_str().map(|host| {
+        if host == "api.github.com" {
+            "github.com".to_owned()
+        } else {
+            host.to_owned()
+        }
+    })
+}
+
+/// Extract the password from the output of `git credential fill`
+fn password_from_credential_output(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find(|line| line.starts_with("password="))
+        .map(|line| line["password=".len()..].to_owned())
+        .filter(|password| !password.is_empty())
+}
+
+fn token_from_git_credential(api_url: &Url) -> Result<String> {
+    let host =
+        credential_host(api_url).ok_or_else(|| anyhow!("Api url {} has no host", api_url))?;
+    let mut child = Command::new("git")
+        .args(["credential", "fill"])
+        // Never prompt the user, we are most likely running in CI
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to run git credential fill")?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("Can't write to git credential stdin"))?
+        .write_all(format!("protocol={}\nhost={}\n\n", api_url.scheme(), host).as_bytes())
+        .context("Failed to write to git credential stdin")?;
+    let output = child
+        .wait_with_output()
+        .context("Failed to run git credential fill")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git credential fill returned unexpected status : {}",
+            output.status
+        ));
+    }
+    password_from_credential_output(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| anyhow!("git credential fill returned no password for {}", host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_token_precedence() {
+        let api_url = Url::from_str("https://api.github.com/").unwrap();
+        let env: HashMap<&str, &str> =
+            vec![("GITHUB_TOKEN", "env_token"), ("GH_TOKEN", "gh_token")]
+                .into_iter()
+                .collect();
+        assert_eq!(
+            resolve_token(Some("arg_token"), None, &TOKEN_ENV_VARS, &env, &api_url).unwrap(),
+            "arg_token"
+        );
+        assert_eq!(
+            resolve_token(None, None, &TOKEN_ENV_VARS, &env, &api_url).unwrap(),
+            "env_token"
+        );
+        let env: HashMap<&str, &str> = vec![("GH_TOKEN", "gh_token\n")].into_iter().collect();
+        assert_eq!(
+            resolve_token(None, None, &TOKEN_ENV_VARS, &env, &api_url).unwrap(),
+            "gh_token"
+        );
+        assert!(resolve_token(
+            None,
+            Some("/does/not/exist"),
+            &TOKEN_ENV_VARS,
+            &env,
+            &api_url
+        )
+        .is_err());
+        let env: HashMap<&str, &str> =
+            vec![("GITHUB_TOKEN", "env_token"), ("GITLAB_TOKEN", "gl_token")]
+                .into_iter()
+                .collect();
+        assert_eq!(
+            resolve_token(None, None, &GITLAB_TOKEN_ENV_VARS, &env, &api_url).unwrap(),
+            "gl_token"
+        );
+    }
+
+    #[test]
+    fn test_credential_host() {
+        assert_eq!(
+            credential_host(&Url::from_str("https://api.github.com/").unwrap()).as_deref(),
+            Some("github.com")
+        );
+        assert_eq!(
+            credential_host(&Url::from_str("https://my.github.internal/api/v3/").unwrap())
+                .as_deref(),
+            Some("my.github.internal")
+        );
+    }
+
+    #[test]
+    fn test_password_from_credential_output() {
+        assert_eq!(
+            password_from_credential_output(
+                "protocol=https\nhost=github.com\nusername=bot\npassword=s3cr3t\n"
+            )
+            .as_deref(),
+            Some("s3cr3t")
+        );
+        assert_eq!(
+            password_from_credential_output("protocol=https\nhost=github.com\n"),
+            None
+        );
+    }
+}